@@ -0,0 +1,6 @@
+/// Exposes the target triple that tempget is compiled for as the
+/// `TEMPGET_TARGET` environment variable.
+fn main() {
+    let target = std::env::var("TARGET").unwrap();
+    println!("cargo:rustc-env=TEMPGET_TARGET={}", target);
+}
@@ -0,0 +1,217 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+
+use crate::template::{ExtractInfo, ExtractTarget, Template};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+/// How serious a problem in a template is.
+pub enum Severity {
+    /// The template works, but probably does not do what was intended.
+    Warning,
+    /// The template will fail or do something dangerous.
+    Error
+}
+
+#[derive(Debug, Clone)]
+/// A problem found while checking a template.
+pub struct Problem {
+    pub severity: Severity,
+    pub message: String
+}
+
+impl Problem {
+    fn warning(message: String) -> Self {
+        Problem { severity: Severity::Warning, message }
+    }
+
+    fn error(message: String) -> Self {
+        Problem { severity: Severity::Error, message }
+    }
+
+    /// Returns true if this problem is an error.
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let label = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error"
+        };
+        write!(f, "{}: {}", label, self.message)
+    }
+}
+
+/// Whether a location on disk is written to as a file or as a directory.
+#[derive(PartialEq)]
+enum OutputKind {
+    File,
+    Directory
+}
+
+/// A location on disk that is written to when the template is run.
+struct Output {
+    /// The normalized path of the location.
+    path: PathBuf,
+    /// A description of the template entry that writes to the location.
+    source: String,
+    kind: OutputKind
+}
+
+/// Checks the template for problems without downloading anything. Problems
+/// are returned in the order in which they are found.
+pub fn check_template(templ: &Template) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut outputs = Vec::new();
+
+    let mut retrieve: Vec<_> = templ.retrieve.iter().collect();
+    retrieve.sort_by(|a, b| a.0.cmp(b.0));
+    for (path_str, info) in retrieve {
+        let source = format!("retrieve {:?}", path_str);
+        for url in std::iter::once(info.url()).chain(info.mirrors()) {
+            if url.scheme() != "http" && url.scheme() != "https" {
+                problems.push(Problem::error(format!(
+                    "{}: unsupported URL scheme in {}", source, url)));
+            } else if url.host().is_none() {
+                problems.push(Problem::error(format!("{}: URL {} has no host", source, url)));
+            } else if url.scheme() == "http" {
+                problems.push(Problem::warning(format!(
+                    "{}: URL {} does not use HTTPS", source, url)));
+            }
+        }
+        if let Some(sha256) = info.sha256() {
+            if !crate::checksum::is_sha256(sha256) {
+                problems.push(Problem::error(format!(
+                    "{}: {:?} is not a valid SHA-256 checksum", source, sha256)));
+            }
+        }
+        // Paths ending in `/` are directories that the file is downloaded into.
+        let kind = if path_str.ends_with('/') { OutputKind::Directory } else { OutputKind::File };
+        add_output(&mut problems, &mut outputs, path_str, source, kind);
+    }
+
+    let retrieved: HashSet<PathBuf> = templ.retrieve.keys()
+        .filter_map(|p| normalize(p))
+        .collect();
+    let mut extract: Vec<_> = templ.extract.iter().collect();
+    extract.sort_by(|a, b| a.0.cmp(b.0));
+    for (archive, info) in extract {
+        let source = format!("extract {:?}", archive);
+        match normalize(archive) {
+            Some(path) => {
+                if !retrieved.contains(&path) && !Path::new(archive).exists() {
+                    problems.push(Problem::error(format!(
+                        "{}: the archive is not retrieved and does not exist", source)));
+                }
+            },
+            None => problems.push(Problem::error(format!(
+                "{}: the archive path escapes the current directory", source)))
+        }
+        add_extract_outputs(&mut problems, &mut outputs, &source, info);
+    }
+
+    check_overlaps(&mut problems, &outputs);
+    problems
+}
+
+/// Records the locations that an archive is extracted to, including the
+/// locations that the archives nested inside of it are extracted to.
+fn add_extract_outputs(problems: &mut Vec<Problem>, outputs: &mut Vec<Output>, source: &str,
+                       info: &ExtractInfo) {
+    match info.target() {
+        ExtractTarget::Directory(dir) => {
+            add_output(problems, outputs, dir, source.to_owned(), OutputKind::Directory);
+        },
+        ExtractTarget::Mapping(files) => {
+            let mut files: Vec<_> = files.iter().collect();
+            files.sort_by(|a, b| a.0.cmp(b.0));
+            for (file, dest) in files {
+                let source = format!("{} entry {:?}", source, file);
+                match (dest.path(), dest.nested()) {
+                    (Some(path), _) => {
+                        let kind = if path.ends_with('/') {
+                            OutputKind::Directory
+                        } else {
+                            OutputKind::File
+                        };
+                        add_output(problems, outputs, path, source, kind);
+                    },
+                    (None, Some(nested)) => add_extract_outputs(problems, outputs, &source, nested),
+                    (None, None) => {}
+                }
+            }
+        }
+    }
+}
+
+/// Records a location that the template writes to, or reports an error if
+/// the location is outside of the current directory.
+fn add_output(problems: &mut Vec<Problem>, outputs: &mut Vec<Output>, path: &str,
+              source: String, kind: OutputKind) {
+    match normalize(path) {
+        Some(path) => outputs.push(Output { path, source, kind }),
+        None => problems.push(Problem::error(format!(
+            "{}: the path {:?} escapes the current directory", source, path)))
+    }
+}
+
+/// Reports locations that are written to more than once, and files that are
+/// written inside of other files.
+fn check_overlaps(problems: &mut Vec<Problem>, outputs: &[Output]) {
+    let mut by_path = BTreeMap::<&Path, Vec<&Output>>::new();
+    for out in outputs {
+        by_path.entry(&out.path).or_default().push(out);
+    }
+
+    for (path, outs) in &by_path {
+        if outs.len() > 1 {
+            let sources = outs.iter()
+                .map(|o| o.source.as_str())
+                .collect::<Vec<_>>()
+                .join(", ");
+            if outs.iter().any(|o| o.kind == OutputKind::File) {
+                problems.push(Problem::error(format!(
+                    "{:?} is the destination of multiple entries: {}", path, sources)));
+            } else {
+                problems.push(Problem::warning(format!(
+                    "multiple entries write into the directory {:?}: {}", path, sources)));
+            }
+        }
+
+        for ancestor in path.ancestors().skip(1) {
+            let files = by_path.get(ancestor)
+                .into_iter()
+                .flatten()
+                .filter(|o| o.kind == OutputKind::File);
+            for file in files {
+                for out in outs {
+                    problems.push(Problem::error(format!(
+                        "{} writes to {:?}, which is inside of the file written by {}",
+                        out.source, path, file.source)));
+                }
+            }
+        }
+    }
+}
+
+/// Lexically normalizes a path by resolving `.` and `..` components. Returns
+/// `None` if the path is absolute or escapes the current directory.
+fn normalize(path: &str) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir => return None,
+            Component::CurDir => {},
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    return None;
+                }
+            },
+            Component::Normal(part) => normalized.push(part)
+        }
+    }
+    Some(normalized)
+}
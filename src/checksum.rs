@@ -0,0 +1,62 @@
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+/// Computes the SHA-256 checksum of everything read from the reader, as a
+/// lowercase hexadecimal string.
+pub fn sha256_reader<R: Read>(mut reader: R) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buf = [0; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.input(&buf[..n]);
+    }
+    Ok(format!("{:x}", hasher.result()))
+}
+
+/// Computes the SHA-256 checksum of the file at the given path.
+pub fn sha256_file(path: &Path) -> io::Result<String> {
+    sha256_reader(fs::File::open(path)?)
+}
+
+/// A writer that computes the SHA-256 checksum and the size of everything
+/// written to it, before passing it on to another writer.
+pub struct Sha256Writer<W> {
+    inner: W,
+    hasher: Sha256,
+    len: u64
+}
+
+impl<W: Write> Sha256Writer<W> {
+    pub fn new(inner: W) -> Self {
+        Sha256Writer { inner, hasher: Sha256::new(), len: 0 }
+    }
+
+    /// Returns the inner writer, the number of bytes written, and the
+    /// checksum as a lowercase hexadecimal string.
+    pub fn finish(self) -> (W, u64, String) {
+        (self.inner, self.len, format!("{:x}", self.hasher.result()))
+    }
+}
+
+impl<W: Write> Write for Sha256Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.input(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Returns true if the string is a well-formed SHA-256 checksum.
+pub fn is_sha256(s: &str) -> bool {
+    s.len() == 64 && s.chars().all(|c| c.is_ascii_hexdigit())
+}
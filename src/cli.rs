@@ -8,24 +8,285 @@ use number_prefix::NumberPrefix;
 use structopt::StructOpt;
 
 use crate::errors;
+use crate::export::ExportFormat;
+use crate::extract::ExtractLimits;
+use crate::filter::Filter;
+use crate::init::InputFormat;
+use crate::platform::Platform;
+use crate::template::{Format, LoadOptions, TemplateSource};
 
 #[derive(StructOpt, Debug, Clone)]
-#[structopt(name = "tempget", about = "Downloads files based on a template")]
+#[structopt(name = "tempget", about = "Downloads files based on a template",
+            raw(setting = "structopt::clap::AppSettings::ArgRequiredElseHelp"),
+            raw(setting = "structopt::clap::AppSettings::ArgsNegateSubcommands"))]
 pub struct CliOptions {
-    #[structopt(parse(from_os_str))]
-    /// The template file to use.
-    pub template_file: PathBuf,
+    #[structopt(parse(from_os_str = "TemplateSource::from_os_str"))]
+    /// The template file to use. Equivalent to `tempget fetch <template_file>`.
+    pub template_file: Option<TemplateSource>,
+    #[structopt(flatten)]
+    pub fetch: FetchOptions,
+    #[structopt(subcommand)]
+    pub command: Option<Command>
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub enum Command {
+    #[structopt(name = "fetch")]
+    /// Downloads and extracts the files specified by a template.
+    Fetch(FetchCommand),
+    #[structopt(name = "check")]
+    /// Checks a template for problems without downloading anything.
+    Check(CheckCommand),
+    #[structopt(name = "init")]
+    /// Creates a template from a list of URLs, an aria2 input file, or a
+    /// Metalink document.
+    Init(InitCommand),
+    #[structopt(name = "export")]
+    /// Converts a template into a shell script, PowerShell script, or aria2
+    /// input file.
+    Export(ExportCommand)
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct FetchCommand {
+    #[structopt(parse(from_os_str = "TemplateSource::from_os_str"))]
+    /// The template file to use. May be `-` to read the template from standard
+    /// input, or an `http://` or `https://` URL.
+    pub template_file: TemplateSource,
+    #[structopt(flatten)]
+    pub options: FetchOptions
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct CheckCommand {
+    #[structopt(parse(from_os_str = "TemplateSource::from_os_str"))]
+    /// The template file to check. May be `-` to read the template from
+    /// standard input, or an `http://` or `https://` URL.
+    pub template_file: TemplateSource,
+    #[structopt(flatten)]
+    pub template: TemplateOptions
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct InitCommand {
+    #[structopt(parse(from_os_str = "TemplateSource::from_os_str"))]
+    /// The file containing the URLs. May be `-` to read the URLs from standard
+    /// input, or an `http://` or `https://` URL.
+    pub input: TemplateSource,
+    #[structopt(long = "from", raw(possible_values = r#"&["list", "aria2", "metalink"]"#))]
+    /// The format of the input: a list of URLs with one URL per line, an aria2
+    /// input file, or a Metalink 4 document. Defaults to `metalink` for
+    /// `.meta4` files and `list` otherwise.
+    pub from: Option<InputFormat>,
+    #[structopt(short = "o", long = "output", parse(from_os_str))]
+    /// The file to write the template to. Defaults to standard output.
+    pub output: Option<PathBuf>,
+    #[structopt(long = "format", raw(possible_values = r#"&["toml", "json"]"#))]
+    /// The format of the template. Defaults to the format indicated by the
+    /// file extension of the output file, or TOML.
+    pub format: Option<Format>,
+    #[structopt(long = "checksums")]
+    /// When this flag is present, each file in a list of URLs is downloaded
+    /// once to record its SHA-256 checksum in the template.
+    pub checksums: bool,
+    /// The maximum amount of time (in seconds) to wait for each request.
+    #[structopt(long, default_value = "10")]
+    pub timeout: u64
+}
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct ExportCommand {
+    #[structopt(parse(from_os_str = "TemplateSource::from_os_str"))]
+    /// The template file to export. May be `-` to read the template from
+    /// standard input, or an `http://` or `https://` URL.
+    pub template_file: TemplateSource,
+    #[structopt(long = "format", raw(possible_values = r#"&["sh", "aria2", "powershell"]"#))]
+    /// The format to export the template to.
+    pub format: ExportFormat,
+    #[structopt(short = "o", long = "output", parse(from_os_str))]
+    /// The file to write to. Defaults to standard output.
+    pub output: Option<PathBuf>,
+    #[structopt(long = "var", raw(number_of_values = "1"), parse(try_from_str = "parse_var"))]
+    /// Sets a template variable, overriding its value in the template. Given
+    /// as `name=value`. May be given multiple times.
+    pub vars: Vec<(String, String)>,
+    #[structopt(long = "target")]
+    /// The target triple (e.g. `x86_64-pc-windows-msvc`) to export the
+    /// template for. Defaults to the platform that tempget is running on.
+    pub target: Option<String>,
+    #[structopt(long = "template-format", raw(possible_values = r#"&["toml", "json", "yaml"]"#))]
+    /// The format of the template. Defaults to the format indicated by the
+    /// file extension, or TOML if the extension is not recognized.
+    pub template_format: Option<Format>,
+    #[structopt(long = "template-sha256")]
+    /// The expected SHA-256 checksum of the template.
+    pub template_sha256: Option<String>,
+    /// The maximum amount of time (in seconds) to wait when reading Metalink
+    /// documents or determining the names of files in directory entries.
+    #[structopt(long, default_value = "10")]
+    pub timeout: u64
+}
+
+impl ExportCommand {
+    /// Returns the options used to load the template.
+    pub fn load_options(&self) -> LoadOptions {
+        load_options(&self.vars, &self.target, self.template_format, &self.template_sha256)
+    }
+}
+
+#[derive(StructOpt, Debug, Clone)]
+/// Options that control how a template is loaded.
+pub struct TemplateOptions {
+    #[structopt(long = "var", raw(number_of_values = "1"), parse(try_from_str = "parse_var"))]
+    /// Sets a template variable, overriding its value in the template. Given
+    /// as `name=value`. May be given multiple times.
+    pub vars: Vec<(String, String)>,
+    #[structopt(long = "target")]
+    /// The target triple (e.g. `x86_64-pc-windows-msvc`) to retrieve files
+    /// for. Defaults to the platform that tempget is running on.
+    pub target: Option<String>,
+    #[structopt(long = "format", raw(possible_values = r#"&["toml", "json", "yaml"]"#))]
+    /// The format of the template. Defaults to the format indicated by the
+    /// file extension, or TOML if the extension is not recognized.
+    pub format: Option<Format>,
+    #[structopt(long = "template-sha256")]
+    /// The expected SHA-256 checksum of the template. If the template does not
+    /// match the checksum, nothing is downloaded.
+    pub template_sha256: Option<String>
+}
+
+impl TemplateOptions {
+    /// Returns the options used to load the template.
+    pub fn load_options(&self) -> LoadOptions {
+        load_options(&self.vars, &self.target, self.format, &self.template_sha256)
+    }
+}
+
+/// Returns the options used to load a template, given the values of the
+/// corresponding command line options.
+fn load_options(vars: &[(String, String)], target: &Option<String>, format: Option<Format>,
+                sha256: &Option<String>) -> LoadOptions {
+    let platform = match target {
+        Some(target) => Platform::from_target(target),
+        None => Platform::host()
+    };
+    LoadOptions {
+        vars: vars.iter().cloned().collect(),
+        platform,
+        format,
+        sha256: sha256.clone()
+    }
+}
+
+/// Parses a umask given in octal.
+fn parse_umask(s: &str) -> Result<u32, String> {
+    match u32::from_str_radix(s, 8) {
+        Ok(mask) if mask <= 0o7777 => Ok(mask),
+        _ => Err(format!("expected an octal umask such as 022, found {:?}", s))
+    }
+}
+
+/// Parses a limit given as a number with an optional `K`, `M`, `G`, or `T`
+/// suffix (in powers of 1024).
+fn parse_limit(s: &str) -> Result<u64, String> {
+    let (digits, shift) = match s.char_indices().last() {
+        Some((i, 'K')) => (&s[..i], 10),
+        Some((i, 'M')) => (&s[..i], 20),
+        Some((i, 'G')) => (&s[..i], 30),
+        Some((i, 'T')) => (&s[..i], 40),
+        _ => (s, 0)
+    };
+    digits.parse::<u64>().ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("expected a number such as 500M, found {:?}", s))
+}
+
+/// Parses a variable given as `name=value`.
+fn parse_var(s: &str) -> Result<(String, String), String> {
+    match s.find('=') {
+        Some(idx) => Ok((s[..idx].to_owned(), s[idx + 1..].to_owned())),
+        None => Err(format!("expected `name=value`, found {:?}", s))
+    }
+}
+
+#[derive(StructOpt, Debug, Clone)]
+/// Options that control how files are downloaded and extracted.
+pub struct FetchOptions {
+    #[structopt(flatten)]
+    pub template: TemplateOptions,
     #[structopt(long = "no-extract")]
     /// When this flag is present, files are not extracted from the given zip
     /// files.
     pub no_extract: bool,
     #[structopt(short = "p", long = "parallelism", default_value = "4")]
-    /// The maximum number of files that should be downloaded simultaneously.
+    /// The maximum number of files that should be downloaded simultaneously,
+    /// and of archives that should be extracted simultaneously.
     pub parallelism: usize,
     /// The maximum amount of time (in seconds) to wait to connect or receive
     /// data before failing the download.
     #[structopt(long, default_value = "10")]
-    pub timeout: u64
+    pub timeout: u64,
+    #[structopt(long = "dry-run")]
+    /// When this flag is present, the files that would be downloaded and
+    /// extracted are printed, but nothing is written to disk.
+    pub dry_run: bool,
+    #[structopt(long = "umask", default_value = "022", parse(try_from_str = "parse_umask"))]
+    /// The permission bits (in octal) to clear from the modes of extracted
+    /// files.
+    pub umask: u32,
+    #[structopt(long = "max-extract-size", default_value = "16G",
+                parse(try_from_str = "parse_limit"))]
+    /// The maximum number of bytes to extract from each archive, such as
+    /// 500M or 2G, or 0 for no limit.
+    pub max_extract_size: u64,
+    #[structopt(long = "max-entry-size", default_value = "4G",
+                parse(try_from_str = "parse_limit"))]
+    /// The maximum number of bytes to extract from a single file in an
+    /// archive, or 0 for no limit.
+    pub max_entry_size: u64,
+    #[structopt(long = "max-entries", default_value = "100000")]
+    /// The maximum number of files to extract from each archive, or 0 for no
+    /// limit.
+    pub max_entries: u64,
+    #[structopt(long = "max-ratio", default_value = "200")]
+    /// The maximum compression ratio of a file in a zip archive that is
+    /// larger than 1M, or 0 for no limit.
+    pub max_ratio: u64,
+    #[structopt(long = "only", raw(number_of_values = "1"))]
+    /// Only retrieve and extract files whose paths match the given glob
+    /// pattern. May be given multiple times.
+    pub only: Vec<glob::Pattern>,
+    #[structopt(long = "exclude", raw(number_of_values = "1"))]
+    /// Do not retrieve or extract files whose paths match the given glob
+    /// pattern. May be given multiple times.
+    pub exclude: Vec<glob::Pattern>,
+    #[structopt(long = "group", raw(number_of_values = "1"))]
+    /// Only retrieve files that belong to the given group. May be given
+    /// multiple times.
+    pub groups: Vec<String>
+}
+
+impl FetchOptions {
+    /// Returns the filter that selects the files to retrieve and extract.
+    pub fn filter(&self) -> Filter {
+        Filter {
+            only: self.only.clone(),
+            exclude: self.exclude.clone(),
+            groups: self.groups.clone()
+        }
+    }
+
+    /// Returns the limits on what is extracted from each archive. Limits of 0
+    /// are not checked.
+    pub fn limits(&self) -> ExtractLimits {
+        let limit = |n: u64| Some(n).filter(|&n| n > 0);
+        ExtractLimits {
+            max_size: limit(self.max_extract_size),
+            max_entry_size: limit(self.max_entry_size),
+            max_entries: limit(self.max_entries),
+            max_ratio: limit(self.max_ratio)
+        }
+    }
 }
 
 /// A message indicating the progress made by a file with the given id.
@@ -38,19 +299,38 @@ pub enum DownloadStatus {
     Progress(usize, usize, Instant),
     /// Download finished
     Finish(usize),
+    /// Download failed, and will be retried from the given mirror
+    Retry(usize, errors::Error, Url),
     /// Download failed
-    Failed(usize, errors::Error)
+    Failed(usize, errors::Error),
+    /// A message about the file, such as a file that was extracted from it
+    Message(usize, String),
+    /// Extraction of an archive started, with the number of bytes to extract
+    ExtractStart(usize, Option<u64>),
+    /// Extraction in progress, with the amount of bytes last extracted and the timestamp
+    ExtractProgress(usize, u64, Instant),
+    /// Extraction finished
+    ExtractFinish(usize),
+    /// Extraction failed
+    ExtractFailed(usize, errors::Error)
 }
 
 impl DownloadStatus {
-    /// Returns the id of the file that this status represents.
+    /// Returns the id of the file that this status represents. The statuses
+    /// of extractions have the id of the extraction instead.
     pub fn get_index(&self) -> &usize {
         match self {
             DownloadStatus::Init(idx) => idx,
             DownloadStatus::Start(idx, _) => idx,
             DownloadStatus::Progress(idx, _, _) => idx,
             DownloadStatus::Finish(idx) => idx,
+            DownloadStatus::Retry(idx, _, _) => idx,
             DownloadStatus::Failed(idx, _) => idx,
+            DownloadStatus::Message(idx, _) => idx,
+            DownloadStatus::ExtractStart(id, _) => id,
+            DownloadStatus::ExtractProgress(id, _, _) => id,
+            DownloadStatus::ExtractFinish(id) => id,
+            DownloadStatus::ExtractFailed(id, _) => id,
         }
     }
 }
@@ -164,18 +444,24 @@ impl ProgressRender {
     }
 }
 
-/// Keeps track of the download progress for each file being downloaded. The
-/// progress of each file is treated as a state machine, where the states
-/// consist of `DownloadState`s. The states can be updated by calling the
-/// appropriate methods, such as `mark_current` or `inc_progress`.
+/// Keeps track of the download progress for each file being downloaded, and
+/// of the progress of each archive being extracted. The progress of each file
+/// is treated as a state machine, where the states consist of
+/// `DownloadState`s. The states can be updated by calling the appropriate
+/// methods, such as `mark_current` or `inc_progress`.
 pub struct ProgressState {
     /// Maps file id to location on disk and URL
     pub file_info: HashMap<usize, (PathBuf, Url)>,
     /// Tracks file download state information
     states: HashMap<usize, DownloadState>,
+    /// Maps extraction id to the archive being extracted
+    extract_info: HashMap<usize, PathBuf>,
+    /// Tracks extraction state information. Extractions are never
+    /// `Connecting`.
+    extract_states: HashMap<usize, DownloadState>
 }
 
-/// Download progress state for one file.
+/// Progress state for one download or extraction.
 enum DownloadState {
     /// The file is currently queued for download.
     Queued,
@@ -189,6 +475,13 @@ enum DownloadState {
     Failed(errors::Error)
 }
 
+impl DownloadState {
+    /// Returns true if the download or extraction has finished or failed.
+    fn is_ended(&self) -> bool {
+        matches!(self, DownloadState::Finished | DownloadState::Failed(_))
+    }
+}
+
 impl ProgressState {
     pub fn new(file_info: HashMap<usize, (PathBuf, Url)>) -> Self {
         let init_state = file_info.iter()
@@ -197,12 +490,16 @@ impl ProgressState {
         ProgressState {
             states: init_state,
             file_info: file_info,
+            extract_info: HashMap::new(),
+            extract_states: HashMap::new()
         }
     }
 
-    /// Returns true when each file is downloaded or has failed to download.
+    /// Returns true when each file is downloaded or has failed to download,
+    /// and each archive is extracted or has failed to extract.
     pub fn is_done(&self) -> bool {
         self.file_info.len() == self.ended().len()
+            && self.extract_states.values().all(DownloadState::is_ended)
     }
 
     /// Returns the indexes of all of the finished downloads.
@@ -267,6 +564,17 @@ impl ProgressState {
         });
     }
 
+    /// Marks the file with the given id as connecting again after a failed
+    /// attempt, discarding its progress. Does nothing if the file has already
+    /// finished or failed.
+    pub fn mark_retry(&mut self, id: &usize) {
+        self.states.entry(*id).and_modify(|st| {
+            if let DownloadState::InProgress(_) = st {
+                *st = DownloadState::Connecting;
+            }
+        });
+    }
+
     /// Marks the file with the given id as finished downloading. Does nothing
     /// if the file is not downloading.
     pub fn mark_finished(&mut self, id: &usize) {
@@ -287,6 +595,84 @@ impl ProgressState {
         });
     }
 
+    /// Starts tracking the extraction of the archive with the given id, which
+    /// is queued until `mark_extract_start` is called.
+    pub fn add_extraction(&mut self, id: usize, path: PathBuf) {
+        self.extract_info.insert(id, path);
+        self.extract_states.insert(id, DownloadState::Queued);
+    }
+
+    /// Marks the extraction with the given id as in progress if it is queued.
+    pub fn mark_extract_start(&mut self, id: &usize, size_opt: Option<u64>) {
+        self.extract_states.entry(*id).and_modify(|st| {
+            if let DownloadState::Queued = st {
+                *st = DownloadState::InProgress(FileDownloadProgress::new(size_opt));
+            }
+        });
+    }
+
+    /// Increases the progress of the extraction with the given id if it is in
+    /// progress, or does nothing otherwise.
+    pub fn inc_extract_progress(&mut self, id: usize, amount: u64, timestamp: &Instant) {
+        self.extract_states.entry(id).and_modify(|st| {
+            if let DownloadState::InProgress(prog) = st {
+                prog.inc(amount, timestamp);
+            }
+        });
+    }
+
+    /// Marks the extraction with the given id as finished. Does nothing if the
+    /// extraction has failed.
+    pub fn mark_extract_finished(&mut self, id: &usize) {
+        self.extract_states.entry(*id).and_modify(|st| {
+            if !st.is_ended() {
+                *st = DownloadState::Finished;
+            }
+        });
+    }
+
+    /// Marks the extraction with the given id as failed.
+    pub fn mark_extract_failed(&mut self, id: &usize, err: errors::Error) {
+        self.extract_states.entry(*id).and_modify(|st| {
+            if !st.is_ended() {
+                *st = DownloadState::Failed(err);
+            }
+        });
+    }
+
+    /// Returns the path of the archive that the extraction with the given id
+    /// extracts, or `None` if there is no such extraction.
+    pub fn get_extract_path(&self, id: &usize) -> Option<&Path> {
+        self.extract_info.get(id).map(PathBuf::as_path)
+    }
+
+    /// Returns the error that caused the extraction with the given id to
+    /// fail, or `None` if there is no such extraction or it has not failed.
+    pub fn get_extract_error(&self, id: &usize) -> Option<&errors::Error> {
+        self.extract_states.get(id).and_then(|st| {
+            if let DownloadState::Failed(err) = st { Some(err) } else { None }
+        })
+    }
+
+    /// Removes the failed extractions, returning the paths of their archives
+    /// along with the errors that caused them to fail, sorted by path.
+    pub fn take_extract_failures(&mut self) -> Vec<(PathBuf, errors::Error)> {
+        let failed: Vec<usize> = self.extract_states.iter()
+            .filter(|(_, st)| matches!(st, DownloadState::Failed(_)))
+            .map(|(id, _)| *id)
+            .collect();
+        let mut failures: Vec<_> = failed.into_iter()
+            .filter_map(|id| match self.extract_states.remove(&id) {
+                Some(DownloadState::Failed(err)) => {
+                    Some((self.extract_info.remove(&id).unwrap(), err))
+                },
+                _ => None
+            })
+            .collect();
+        failures.sort_by(|a, b| a.0.cmp(&b.0));
+        failures
+    }
+
     /// Increases the progress of the file with the given id if it is being
     /// downloaded, or does nothing otherwise.
     pub fn inc_progress(&mut self, id: usize, amount: u64, timestamp: &Instant)  {
@@ -324,14 +710,14 @@ impl ProgressState {
     }
 
     /// Displays the size number, along with its units.
-    fn display_bytes(size: u64) -> String {
+    pub fn display_bytes(size: u64) -> String {
         match NumberPrefix::decimal(size as f64) {
             NumberPrefix::Standalone(_) => format!("{} bytes", size),
             NumberPrefix::Prefixed(units, n) => format!("{:.2} {}B", n, units)
         }
     }
 
-    /// Renders the download progress to a `Vec<String>`
+    /// Renders the download and extraction progress to a `Vec<String>`
     pub fn render(&self) -> Vec<String> {
         let mut lines = Vec::new();
         let considered = self.processing().into_iter().collect::<Vec<usize>>();
@@ -346,22 +732,70 @@ impl ProgressState {
                         lines.push(format!("{}\tconnecting", path_str));
                     },
                     DownloadState::InProgress(progress) => {
-                        let down_bytes = Self::display_bytes(progress.down_size);
-                        let rate_bytes = Self::display_bytes(progress.last_update_rate);
-                        if let Some(max_size) = &progress.max_size {
-                            let total_bytes = Self::display_bytes(*max_size);
-                            let percent = 100.0 * (progress.down_size as f64)
-                                / (*max_size as f64);
-                            lines.push(format!("{}\t{} / {} ({:.2}%), {}/s",
-                                               path_str, down_bytes, total_bytes, percent, rate_bytes));
-                        } else {
-                            lines.push(format!("{}\t{}, {}/s", path_str, down_bytes, rate_bytes));
-                        }
+                        lines.push(Self::render_progress(&path_str, progress));
                     },
                     _ => ()
                 };
             }
         }
+
+        let mut extracting: Vec<_> = self.extract_states.iter()
+            .filter_map(|(id, st)| match st {
+                DownloadState::InProgress(progress) => Some((&self.extract_info[id], progress)),
+                _ => None
+            })
+            .collect();
+        if !extracting.is_empty() {
+            extracting.sort_by(|a, b| a.0.cmp(b.0));
+            let ended = self.extract_states.values().filter(|st| st.is_ended()).count();
+            lines.push(format!("Extracting: ({}/{})", ended, self.extract_states.len()));
+            for (path, progress) in extracting {
+                lines.push(Self::render_progress(&path.to_string_lossy(), progress));
+            }
+        }
         lines
     }
+
+    /// Renders the progress of a single download or extraction.
+    fn render_progress(path_str: &str, progress: &FileDownloadProgress) -> String {
+        let down_bytes = Self::display_bytes(progress.down_size);
+        let rate_bytes = Self::display_bytes(progress.last_update_rate);
+        if let Some(max_size) = &progress.max_size {
+            let total_bytes = Self::display_bytes(*max_size);
+            let percent = 100.0 * (progress.down_size as f64)
+                / (*max_size as f64);
+            format!("{}\t{} / {} ({:.2}%), {}/s",
+                    path_str, down_bytes, total_bytes, percent, rate_bytes)
+        } else {
+            format!("{}\t{}, {}/s", path_str, down_bytes, rate_bytes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits() {
+        assert_eq!(parse_limit("0"), Ok(0));
+        assert_eq!(parse_limit("1234"), Ok(1234));
+        assert_eq!(parse_limit("2K"), Ok(2 << 10));
+        assert_eq!(parse_limit("500M"), Ok(500 << 20));
+        assert_eq!(parse_limit("16G"), Ok(16 << 30));
+        assert_eq!(parse_limit("3T"), Ok(3 << 40));
+        for invalid in &["", "M", "1.5G", "-1", "10k", "10 M", "20000000T"] {
+            assert!(parse_limit(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn plain_limits() {
+        let args = ["fetch", "--max-entries", "10", "--max-ratio", "0", "t.toml"];
+        let options = FetchCommand::from_iter_safe(&args).unwrap().options;
+        assert_eq!(options.max_entries, 10);
+        assert_eq!(options.limits().max_ratio, None);
+        let args = ["fetch", "--max-entries", "10K", "t.toml"];
+        assert!(FetchCommand::from_iter_safe(&args).is_err());
+    }
 }
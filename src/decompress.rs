@@ -0,0 +1,331 @@
+use std::io::{self, Write};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+
+/// The number of bytes needed to detect every supported format.
+const HEADER_LEN: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// A format that a single compressed file can be in.
+pub enum Compression {
+    Gzip,
+    Bzip2,
+    Xz,
+    Zstd
+}
+
+impl Compression {
+    /// All of the supported formats.
+    pub const ALL: [Compression; 4] =
+        [Compression::Gzip, Compression::Bzip2, Compression::Xz, Compression::Zstd];
+
+    /// Returns the file extension used by the format, including the dot.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::Gzip => ".gz",
+            Compression::Bzip2 => ".bz2",
+            Compression::Xz => ".xz",
+            Compression::Zstd => ".zst"
+        }
+    }
+
+    /// Returns the format whose extension the path ends with, if any.
+    pub fn from_extension(path: &str) -> Option<Self> {
+        Compression::ALL.iter().cloned().find(|c| path.ends_with(c.extension()))
+    }
+
+    /// Detects the format from the first bytes of the data.
+    pub fn detect(header: &[u8]) -> Option<Self> {
+        if header.starts_with(&[0x1f, 0x8b]) {
+            Some(Compression::Gzip)
+        } else if header.starts_with(b"BZh") {
+            Some(Compression::Bzip2)
+        } else if header.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Compression::Xz)
+        } else if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the command that decompresses standard input to standard
+    /// output.
+    pub fn command(self) -> &'static [&'static str] {
+        match self {
+            Compression::Gzip => &["gzip", "-dc"],
+            Compression::Bzip2 => &["bzip2", "-dc"],
+            Compression::Xz => &["xz", "-dc"],
+            Compression::Zstd => &["zstd", "-dcq"]
+        }
+    }
+}
+
+/// Returns true if a file downloaded from the URL to the path should be
+/// decompressed when `decompress` is not given: the URL ends with the
+/// extension of a compression format, and the path does not.
+pub fn is_implied(url_path: &str, path: &str) -> bool {
+    match Compression::from_extension(url_path) {
+        Some(compression) => !path.ends_with(compression.extension()),
+        None => false
+    }
+}
+
+/// Decompresses data into a writer.
+enum Decoder<W: Write> {
+    /// Gzip files may consist of several members, which are decompressed one
+    /// after the other.
+    Gzip(Box<flate2::write::MultiGzDecoder<W>>),
+    Bzip2(bzip2::write::BzDecoder<W>),
+    /// Formats that are not supported natively are decompressed by a separate
+    /// program.
+    Process(Process<W>),
+    /// The data is not compressed.
+    Plain(W)
+}
+
+/// A program that decompresses the data written to its standard input. A
+/// thread copies the output of the program to the writer, and another one
+/// collects its error messages. If it is dropped before it is finished, the
+/// program is killed and waited for.
+struct Process<W> {
+    child: Child,
+    copier: Option<thread::JoinHandle<io::Result<W>>>,
+    stderr: Option<thread::JoinHandle<Vec<u8>>>
+}
+
+impl<W: Write + Send + 'static> Process<W> {
+    /// Starts the program, which decompresses into `inner`.
+    fn spawn(command: &[&str], mut inner: W) -> io::Result<Self> {
+        let mut child = Command::new(command[0])
+            .args(&command[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| io::Error::new(err.kind(), format!(
+                "could not run {} to decompress the file: {}", command[0], err)))?;
+        let mut stdout = child.stdout.take().unwrap();
+        let copier = thread::spawn(move || {
+            io::copy(&mut stdout, &mut inner)?;
+            Ok(inner)
+        });
+        let mut stderr = child.stderr.take().unwrap();
+        let stderr = thread::spawn(move || {
+            let mut messages = Vec::new();
+            let _ = io::Read::read_to_end(&mut stderr, &mut messages);
+            messages
+        });
+        Ok(Process { child, copier: Some(copier), stderr: Some(stderr) })
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        let res = match self.child.stdin.as_mut() {
+            Some(stdin) => stdin.write_all(buf),
+            None => Err(io::Error::from(io::ErrorKind::BrokenPipe))
+        };
+        // The program stops reading once the writer fails, in which case the
+        // error of the writer is the one that explains what happened.
+        res.map_err(|err| self.stop().err().unwrap_or(err))
+    }
+
+    /// Waits for the program to decompress the rest of the data. The error
+    /// of the writer is returned if it fails, and otherwise the error
+    /// messages of the program if it fails.
+    fn finish(mut self) -> io::Result<W> {
+        // Close standard input so that the program sees the end of the data.
+        drop(self.child.stdin.take());
+        let status = self.child.wait()?;
+        let inner = self.copier.take().unwrap().join().expect("decompression thread panicked")?;
+        let messages = self.stderr.take().unwrap().join().unwrap_or_default();
+        if status.success() {
+            Ok(inner)
+        } else {
+            let messages = String::from_utf8_lossy(&messages);
+            let message = match messages.trim() {
+                "" => format!("decompression failed ({})", status),
+                messages => format!("decompression failed ({}): {}", status, messages)
+            };
+            Err(io::Error::new(io::ErrorKind::InvalidData, message))
+        }
+    }
+}
+
+impl<W> Process<W> {
+    /// Kills the program and waits for the thread that copies its output,
+    /// returning the error of the writer if there was one.
+    fn stop(&mut self) -> io::Result<()> {
+        drop(self.child.stdin.take());
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        match self.copier.take() {
+            Some(copier) => copier.join()
+                .unwrap_or_else(|_| Err(io::Error::other("decompression thread panicked")))
+                .map(drop),
+            None => Ok(())
+        }
+    }
+}
+
+impl<W> Drop for Process<W> {
+    fn drop(&mut self) {
+        if self.copier.is_some() {
+            let _ = self.stop();
+        }
+        if let Some(stderr) = self.stderr.take() {
+            let _ = stderr.join();
+        }
+    }
+}
+
+/// A writer that decompresses the data written to it into another writer. The
+/// format is detected from the first bytes that are written, so that it does
+/// not depend on the URL.
+pub struct DecompressWriter<W: Write> {
+    /// The writer to write to, until the format is known.
+    inner: Option<W>,
+    /// The first bytes of the data, until the format is known.
+    header: Vec<u8>,
+    /// Whether data that is not compressed is written as is, instead of
+    /// being an error.
+    allow_plain: bool,
+    decoder: Option<Decoder<W>>
+}
+
+impl<W: Write + Send + 'static> DecompressWriter<W> {
+    /// Creates a writer that decompresses data into `inner`.
+    pub fn new(inner: W) -> Self {
+        DecompressWriter {
+            inner: Some(inner),
+            header: Vec::with_capacity(HEADER_LEN),
+            allow_plain: false,
+            decoder: None
+        }
+    }
+
+    /// Creates a writer that decompresses data into `inner` if it is
+    /// compressed, and otherwise writes it as is.
+    pub fn new_optional(inner: W) -> Self {
+        DecompressWriter { allow_plain: true, ..DecompressWriter::new(inner) }
+    }
+
+    /// Starts decompressing once the format is known.
+    fn start(&mut self) -> io::Result<()> {
+        let inner = self.inner.take().expect("decompression has already started");
+        let decoder = match Compression::detect(&self.header) {
+            Some(Compression::Gzip) => {
+                Decoder::Gzip(Box::new(flate2::write::MultiGzDecoder::new(inner)))
+            },
+            Some(Compression::Bzip2) => Decoder::Bzip2(bzip2::write::BzDecoder::new(inner)),
+            Some(compression) => Decoder::Process(Process::spawn(compression.command(), inner)?),
+            None if self.allow_plain => Decoder::Plain(inner),
+            None => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          "the file is not compressed in a supported format"));
+            }
+        };
+        self.decoder = Some(decoder);
+        let header = std::mem::take(&mut self.header);
+        self.write_decoder(&header)
+    }
+
+    fn write_decoder(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self.decoder.as_mut().expect("decompression has not started") {
+            Decoder::Gzip(decoder) => decoder.write_all(buf),
+            Decoder::Bzip2(decoder) => decoder.write_all(buf),
+            Decoder::Process(process) => process.write_all(buf),
+            Decoder::Plain(inner) => inner.write_all(buf)
+        }
+    }
+
+    /// Finishes decompressing, returning an error if the data ended early.
+    /// Returns the writer that the data was decompressed into.
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.decoder.is_none() {
+            self.start()?;
+        }
+        match self.decoder.take().unwrap() {
+            Decoder::Gzip(decoder) => decoder.finish(),
+            Decoder::Bzip2(mut decoder) => decoder.finish(),
+            Decoder::Process(process) => process.finish(),
+            Decoder::Plain(inner) => Ok(inner)
+        }
+    }
+}
+
+impl<W: Write + Send + 'static> Write for DecompressWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.decoder.is_none() {
+            let needed = HEADER_LEN - self.header.len();
+            let (head, rest) = buf.split_at(needed.min(buf.len()));
+            self.header.extend_from_slice(head);
+            if self.header.len() < HEADER_LEN {
+                return Ok(buf.len());
+            }
+            self.start()?;
+            self.write_decoder(rest)?;
+        } else {
+            self.write_decoder(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    /// A writer that fails once more than `limit` bytes have been written.
+    struct LimitedWriter {
+        limit: usize,
+        written: usize
+    }
+
+    impl Write for LimitedWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written += buf.len();
+            if self.written > self.limit {
+                return Err(io::Error::other("the writer is full"));
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn process_output() {
+        let mut process = Process::spawn(&["cat"], Vec::new()).unwrap();
+        process.write_all(b"some data").unwrap();
+        assert_eq!(process.finish().unwrap(), b"some data");
+    }
+
+    #[test]
+    fn process_failure() {
+        let mut process = Process::spawn(&["sh", "-c", "cat >/dev/null; echo broken >&2; exit 3"],
+                                         Vec::new()).unwrap();
+        process.write_all(b"some data").unwrap();
+        let err = process.finish().unwrap_err();
+        assert!(err.to_string().contains("broken"), "{}", err);
+    }
+
+    #[test]
+    fn process_writer_error() {
+        // Once the writer fails, the program is killed by SIGPIPE, but the
+        // error of the writer is reported.
+        let mut process = Process::spawn(&["cat"], LimitedWriter { limit: 1000, written: 0 }).unwrap();
+        let data = vec![0; 1 << 16];
+        let res = (0..1000).try_for_each(|_| process.write_all(&data));
+        let err = match res {
+            Ok(()) => process.finish().err().unwrap(),
+            Err(err) => err
+        };
+        assert_eq!(err.to_string(), "the writer is full");
+    }
+}
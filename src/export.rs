@@ -0,0 +1,690 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::str::FromStr;
+
+use crate::decompress::Compression;
+use crate::extract::is_pattern;
+use crate::tar::is_tar;
+use crate::template::{Destination, ExtractTarget, FileMode, RetrieveInfo, Template};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// A format that a template can be exported to.
+pub enum ExportFormat {
+    /// A POSIX shell script using `curl`, `unzip`, GNU `tar`, and `sha256sum`.
+    Sh,
+    /// An aria2 input file. Archives are not extracted.
+    Aria2,
+    /// A PowerShell script.
+    PowerShell
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sh" => Ok(ExportFormat::Sh),
+            "aria2" => Ok(ExportFormat::Aria2),
+            "powershell" => Ok(ExportFormat::PowerShell),
+            _ => Err(format!("unknown export format: {}", s))
+        }
+    }
+}
+
+/// Converts the template into a script or input file in the given format.
+/// `source` describes where the template came from, and is mentioned in a
+/// comment at the top of the output.
+pub fn export(templ: &Template, format: ExportFormat, source: &str) -> String {
+    match format {
+        ExportFormat::Sh => export_sh(templ, source),
+        ExportFormat::Aria2 => export_aria2(templ, source),
+        ExportFormat::PowerShell => export_powershell(templ, source)
+    }
+}
+
+/// Returns the entries of the template sorted by path, so that the output is
+/// deterministic.
+fn sorted_retrieve(templ: &Template) -> Vec<(&String, &RetrieveInfo)> {
+    let mut retrieve: Vec<_> = templ.retrieve.iter().collect();
+    retrieve.sort_by(|a, b| a.0.cmp(b.0));
+    retrieve
+}
+
+/// Returns the archives to extract, sorted by path.
+fn sorted_extract(templ: &Template) -> Vec<(&String, ExtractTarget<'_>)> {
+    let mut extract: Vec<_> = templ.extract.iter()
+        .map(|(archive, info)| (archive, info.target()))
+        .collect();
+    extract.sort_by(|a, b| a.0.cmp(b.0));
+    extract
+}
+
+/// Returns the files to extract from an archive, sorted by name.
+fn sorted_files(files: &HashMap<String, Destination>) -> Vec<(&String, &Destination)> {
+    let mut files: Vec<_> = files.iter().collect();
+    files.sort_by(|a, b| a.0.cmp(b.0));
+    files
+}
+
+/// Describes the parts of the template that cannot be exported to the given
+/// format. Files matching patterns, nested archives, and archives with
+/// `strip_components` are left out of scripts, as are tar archives in
+/// PowerShell scripts.
+pub fn unsupported(templ: &Template, format: ExportFormat) -> Vec<String> {
+    let mut messages = Vec::new();
+    if format == ExportFormat::Aria2 {
+        // aria2 input files never extract archives, and say which files
+        // must be decompressed.
+        return messages;
+    }
+    for (path, info) in sorted_retrieve(templ) {
+        if !info.decompress(path) {
+            continue;
+        }
+        if format == ExportFormat::PowerShell || sh_decompressor(info).is_none() {
+            messages.push(format!("{} is not downloaded, since decompression is not exported",
+                                  path));
+        }
+    }
+    let mut extract: Vec<_> = templ.extract.iter().collect();
+    extract.sort_by(|a, b| a.0.cmp(b.0));
+    for (archive, info) in extract {
+        if format == ExportFormat::PowerShell && is_tar(archive) {
+            messages.push(format!(
+                "{} is not extracted, since tar archives are not exported to PowerShell", archive));
+            continue;
+        }
+        match info.target() {
+            ExtractTarget::Directory(_) => {
+                if info.strip_components().is_some() {
+                    messages.push(format!(
+                        "{} is not extracted, since strip_components is not exported", archive));
+                }
+            },
+            ExtractTarget::Mapping(files) => {
+                for (name, dest) in sorted_files(files) {
+                    if is_pattern(name) {
+                        messages.push(format!("files matching {} in {} are not exported",
+                                              name, archive));
+                    } else if dest.nested().is_some() {
+                        messages.push(format!("the nested archive {} in {} is not extracted, \
+                                               since nested archives are not exported",
+                                              name, archive));
+                    }
+                }
+            }
+        }
+    }
+    messages
+}
+
+/// Returns the command that decompresses a file in a shell script, if the
+/// format of the file is known from its URL.
+fn sh_decompressor(info: &RetrieveInfo) -> Option<String> {
+    Compression::from_extension(info.url().path()).map(|c| c.command().join(" "))
+}
+
+/// Returns true if leading components are removed from the names of the files
+/// in the archive.
+fn strips_components(templ: &Template, archive: &str) -> bool {
+    templ.extract.get(archive).is_some_and(|info| info.strip_components().is_some())
+}
+
+/// Returns true if the archive should be deleted once it has been extracted.
+/// Scripts always store archives, so streamed archives and archives that are
+/// read partially are deleted as well.
+/// Archives that are not fully extracted by scripts are kept.
+fn deletes_archive(templ: &Template, archive: &str, target: &ExtractTarget<'_>) -> bool {
+    let is_deleted = templ.extract.get(archive)
+        .is_some_and(|info| !info.keeps_archive());
+    let is_exported = match target {
+        ExtractTarget::Directory(_) => !strips_components(templ, archive),
+        ExtractTarget::Mapping(files) => {
+            !files.iter().any(|(name, dest)| is_pattern(name) || dest.nested().is_some())
+        }
+    };
+    is_deleted && is_exported
+}
+
+/// Returns true if the archive is retrieved by an optional entry.
+fn is_optional(templ: &Template, archive: &str) -> bool {
+    templ.retrieve.get(archive).is_some_and(RetrieveInfo::is_optional)
+}
+
+const SH_PRELUDE: &str = r#"set -eu
+
+download() {
+    # Usage: download <path> <url>...
+    path=$1
+    shift
+    if [ -e "$path" ]; then
+        echo "$path exists, skipping"
+        return 0
+    fi
+    mkdir -p "$(dirname "$path")"
+    for url in "$@"; do
+        echo "Downloading $url to $path"
+        if curl -fsSL -o "$path" "$url"; then
+            return 0
+        fi
+    done
+    rm -f "$path"
+    echo "Failed to download $path" >&2
+    return 1
+}
+
+download_decompressed() {
+    # Usage: download_decompressed <path> <decompressor> <url>...
+    path=$1
+    decompressor=$2
+    shift 2
+    if [ -e "$path" ]; then
+        echo "$path exists, skipping"
+        return 0
+    fi
+    mkdir -p "$(dirname "$path")"
+    for url in "$@"; do
+        echo "Downloading $url to $path"
+        if curl -fsSL "$url" | $decompressor > "$path"; then
+            return 0
+        fi
+    done
+    rm -f "$path"
+    echo "Failed to download $path" >&2
+    return 1
+}
+
+check_size() {
+    actual=$(wc -c < "$1" | tr -d ' ')
+    if [ "$actual" -ne "$2" ]; then
+        echo "size mismatch for $1: expected $2 bytes, found $actual bytes" >&2
+        exit 5
+    fi
+}
+
+check_sha256() {
+    if ! echo "$2  $1" | sha256sum -c --quiet -; then
+        echo "checksum mismatch for $1" >&2
+        exit 5
+    fi
+}
+
+extract_file() {
+    # Usage: extract_file <archive> <name in archive> <path>
+    if [ -e "$3" ]; then
+        echo "$3 already exists, skipping"
+        return 0
+    fi
+    mkdir -p "$(dirname "$3")"
+    echo "Extracting $3 from $1"
+    unzip -p "$1" "$2" > "$3" || { rm -f "$3"; return 1; }
+}
+
+extract_tar_file() {
+    # Usage: extract_tar_file <archive> <name in archive> <path>
+    if [ -e "$3" ]; then
+        echo "$3 already exists, skipping"
+        return 0
+    fi
+    mkdir -p "$(dirname "$3")"
+    echo "Extracting $3 from $1"
+    tar -xOf "$1" "$2" > "$3" || { rm -f "$3"; return 1; }
+}
+"#;
+
+/// Exports the template as a POSIX shell script.
+fn export_sh(templ: &Template, source: &str) -> String {
+    let mut out = String::new();
+    writeln!(out, "#!/bin/sh").unwrap();
+    writeln!(out, "# Generated by tempget from {}", one_line(source)).unwrap();
+    out.push_str(SH_PRELUDE);
+
+    let retrieve = sorted_retrieve(templ);
+    if !retrieve.is_empty() {
+        out.push('\n');
+    }
+    for (path, info) in &retrieve {
+        let urls = std::iter::once(info.url())
+            .chain(info.mirrors())
+            .map(|url| sh_quote(url.as_str()))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let command = if !info.decompress(path) {
+            format!("download {} {}", sh_quote(path), urls)
+        } else if let Some(decompressor) = sh_decompressor(info) {
+            format!("download_decompressed {} {} {}", sh_quote(path), sh_quote(&decompressor),
+                    urls)
+        } else {
+            writeln!(out, "# Not exported: {} from {} (unknown compression format)",
+                     one_line(path), info.url()).unwrap();
+            continue;
+        };
+        if info.is_optional() {
+            writeln!(out, "{} || echo {} >&2", command,
+                     sh_quote(&format!("Warning: optional file {} failed to download", path)))
+                .unwrap();
+        } else {
+            writeln!(out, "{}", command).unwrap();
+        }
+    }
+    for (path, info) in &retrieve {
+        if info.size().is_none() && info.sha256().is_none() {
+            continue;
+        }
+        writeln!(out, "if [ -e {} ]; then", sh_quote(path)).unwrap();
+        if let Some(size) = info.size() {
+            writeln!(out, "    check_size {} {}", sh_quote(path), size).unwrap();
+        }
+        if let Some(sha256) = info.sha256() {
+            writeln!(out, "    check_sha256 {} {}", sh_quote(path), sh_quote(sha256)).unwrap();
+        }
+        writeln!(out, "fi").unwrap();
+    }
+    for (path, info) in &retrieve {
+        if let Some(mode) = info.file_mode() {
+            writeln!(out, "if [ -e {} ]; then", sh_quote(path)).unwrap();
+            writeln!(out, "    {}", sh_chmod(mode, path)).unwrap();
+            writeln!(out, "fi").unwrap();
+        }
+    }
+
+    for (archive, target) in sorted_extract(templ) {
+        out.push('\n');
+        // Optional archives are only extracted if they were downloaded.
+        let indent = if is_optional(templ, archive) {
+            writeln!(out, "if [ -e {} ]; then", sh_quote(archive)).unwrap();
+            "    "
+        } else {
+            ""
+        };
+        match target {
+            ExtractTarget::Directory(dir) if strips_components(templ, archive) => {
+                writeln!(out, "{}# Not exported: {} to {} with strip_components",
+                         indent, one_line(archive), one_line(dir)).unwrap();
+            },
+            ExtractTarget::Directory(dir) => {
+                writeln!(out, "{}echo {}", indent,
+                         sh_quote(&format!("Extracting {} to {}", archive, dir))).unwrap();
+                // `-n` and `--skip-old-files` skip files that already exist,
+                // like tempget does.
+                if is_tar(archive) {
+                    writeln!(out, "{}mkdir -p {}", indent, sh_quote(dir)).unwrap();
+                    writeln!(out, "{}tar -xf {} -C {} --skip-old-files", indent,
+                             sh_quote(archive), sh_quote(dir)).unwrap();
+                } else {
+                    writeln!(out, "{}unzip -n -q {} -d {}", indent, sh_quote(archive),
+                             sh_quote(dir)).unwrap();
+                }
+            },
+            ExtractTarget::Mapping(files) => {
+                for (name, dest) in sorted_files(files) {
+                    let path = match dest.path() {
+                        Some(path) => path,
+                        None => {
+                            writeln!(out, "{}# Not exported: nested archive {}", indent,
+                                     one_line(name)).unwrap();
+                            continue;
+                        }
+                    };
+                    if is_pattern(name) {
+                        writeln!(out, "{}# Not exported: files matching {} to {}",
+                                 indent, one_line(name), one_line(path)).unwrap();
+                        continue;
+                    }
+                    if is_tar(archive) {
+                        writeln!(out, "{}extract_tar_file {} {} {}", indent, sh_quote(archive),
+                                 sh_quote(name), sh_quote(path)).unwrap();
+                    } else {
+                        writeln!(out, "{}extract_file {} {} {}", indent, sh_quote(archive),
+                                 sh_quote(&unzip_escape(name)), sh_quote(path)).unwrap();
+                    }
+                    if let Some(mode) = dest.file_mode() {
+                        writeln!(out, "{}{}", indent, sh_chmod(mode, path)).unwrap();
+                    }
+                }
+            }
+        }
+        if deletes_archive(templ, archive, &target) {
+            writeln!(out, "{}rm -f {}", indent, sh_quote(archive)).unwrap();
+        }
+        if !indent.is_empty() {
+            writeln!(out, "fi").unwrap();
+        }
+    }
+    out
+}
+
+/// Returns the shell command that sets the permissions of a file.
+fn sh_chmod(mode: FileMode, path: &str) -> String {
+    match mode {
+        FileMode::Mode(mode) => format!("chmod {:o} {}", mode, sh_quote(path)),
+        FileMode::Executable => format!("chmod +x {}", sh_quote(path))
+    }
+}
+
+/// Quotes a string for use as a single argument in a shell script.
+fn sh_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Escapes the wildcard characters that `unzip` interprets in file names.
+fn unzip_escape(name: &str) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for c in name.chars() {
+        match c {
+            '[' | '*' | '?' => {
+                escaped.push('[');
+                escaped.push(c);
+                escaped.push(']');
+            },
+            _ => escaped.push(c)
+        }
+    }
+    escaped
+}
+
+/// Exports the template as an aria2 input file.
+fn export_aria2(templ: &Template, source: &str) -> String {
+    let mut out = String::new();
+    writeln!(out, "# Generated by tempget from {}", one_line(source)).unwrap();
+    let extract = sorted_extract(templ);
+    if !extract.is_empty() {
+        writeln!(out, "# aria2 does not extract archives. Extract these archives manually:")
+            .unwrap();
+        for (archive, _) in &extract {
+            writeln!(out, "#     {}", one_line(archive)).unwrap();
+        }
+    }
+    for (path, info) in sorted_retrieve(templ) {
+        let urls = std::iter::once(info.url())
+            .chain(info.mirrors())
+            .map(|url| url.as_str())
+            .collect::<Vec<_>>()
+            .join("\t");
+        if info.decompress(path) {
+            writeln!(out, "# {} must be decompressed after downloading", one_line(path)).unwrap();
+        }
+        writeln!(out, "{}", urls).unwrap();
+        writeln!(out, "  out={}", one_line(path)).unwrap();
+        if let Some(sha256) = info.sha256() {
+            writeln!(out, "  checksum=sha-256={}", one_line(sha256)).unwrap();
+        }
+    }
+    out
+}
+
+const POWERSHELL_PRELUDE: &str = r#"$ErrorActionPreference = 'Stop'
+Add-Type -AssemblyName System.IO.Compression.FileSystem
+
+function New-ParentDirectory([string]$Path) {
+    $dir = Split-Path -Parent $Path
+    if ($dir) { New-Item -ItemType Directory -Force -Path $dir | Out-Null }
+}
+
+function Get-RemoteFile([string]$Path, [string[]]$Urls) {
+    if (Test-Path -LiteralPath $Path) {
+        Write-Host "$Path exists, skipping"
+        return
+    }
+    New-ParentDirectory $Path
+    foreach ($url in $Urls) {
+        Write-Host "Downloading $url to $Path"
+        try {
+            Invoke-WebRequest -Uri $url -OutFile $Path -UseBasicParsing
+            return
+        } catch {
+            Write-Warning "Failed to download ${Path} from ${url}: $_"
+        }
+    }
+    # Remove what was written, so that the next run does not skip the file.
+    Remove-Item -LiteralPath $Path -Force -ErrorAction SilentlyContinue
+    throw "Failed to download $Path"
+}
+
+function Test-FileSize([string]$Path, [long]$Size) {
+    $actual = (Get-Item -LiteralPath $Path).Length
+    if ($actual -ne $Size) {
+        throw "size mismatch for ${Path}: expected $Size bytes, found $actual bytes"
+    }
+}
+
+function Test-Sha256([string]$Path, [string]$Sha256) {
+    $actual = (Get-FileHash -Algorithm SHA256 -LiteralPath $Path).Hash
+    if ($actual -ne $Sha256) {
+        throw "checksum mismatch for ${Path}: expected $Sha256, found $actual"
+    }
+}
+
+function Expand-Entry($Entry, [string]$Path) {
+    if (Test-Path -LiteralPath $Path) {
+        Write-Host "$Path already exists, skipping"
+        return
+    }
+    New-ParentDirectory $Path
+    Write-Host "Extracting $($Entry.FullName) to $Path"
+    $fullPath = $ExecutionContext.SessionState.Path.GetUnresolvedProviderPathFromPSPath($Path)
+    [System.IO.Compression.ZipFileExtensions]::ExtractToFile($Entry, $fullPath)
+}
+
+function Expand-ArchiveTo([string]$Archive, [string]$Directory) {
+    $root = $ExecutionContext.SessionState.Path.GetUnresolvedProviderPathFromPSPath($Directory)
+    $root = [System.IO.Path]::GetFullPath($root).TrimEnd('\', '/') + [System.IO.Path]::DirectorySeparatorChar
+    $zip = [System.IO.Compression.ZipFile]::OpenRead((Resolve-Path -LiteralPath $Archive))
+    try {
+        foreach ($entry in $zip.Entries) {
+            if ($entry.FullName.EndsWith('/')) { continue }
+            # Entries must not be written outside of the directory.
+            $path = [System.IO.Path]::GetFullPath((Join-Path $root $entry.FullName))
+            if (-not $path.StartsWith($root, [System.StringComparison]::Ordinal)) {
+                throw "$($entry.FullName) in $Archive is outside of $Directory"
+            }
+            Expand-Entry $entry $path
+        }
+    } finally {
+        $zip.Dispose()
+    }
+}
+
+function Expand-ArchiveFile([string]$Archive, [string]$Name, [string]$Path) {
+    $zip = [System.IO.Compression.ZipFile]::OpenRead((Resolve-Path -LiteralPath $Archive))
+    try {
+        $entry = $zip.GetEntry($Name)
+        if ($entry -eq $null) { throw "$Name not found in $Archive" }
+        Expand-Entry $entry $Path
+    } finally {
+        $zip.Dispose()
+    }
+}
+"#;
+
+/// Exports the template as a PowerShell script.
+fn export_powershell(templ: &Template, source: &str) -> String {
+    let mut out = String::new();
+    writeln!(out, "# Generated by tempget from {}", one_line(source)).unwrap();
+    out.push_str(POWERSHELL_PRELUDE);
+
+    let retrieve = sorted_retrieve(templ);
+    if !retrieve.is_empty() {
+        out.push('\n');
+    }
+    for (path, info) in &retrieve {
+        let urls = std::iter::once(info.url())
+            .chain(info.mirrors())
+            .map(|url| ps_quote(url.as_str()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        if info.decompress(path) {
+            writeln!(out, "# Not exported: {} from {} (decompression)", one_line(path),
+                     info.url()).unwrap();
+            continue;
+        }
+        if info.is_optional() {
+            writeln!(out, "try {{ Get-RemoteFile {} @({}) }} catch {{ Write-Warning $_ }}",
+                     ps_quote(path), urls).unwrap();
+        } else {
+            writeln!(out, "Get-RemoteFile {} @({})", ps_quote(path), urls).unwrap();
+        }
+    }
+    for (path, info) in &retrieve {
+        if info.size().is_none() && info.sha256().is_none() {
+            continue;
+        }
+        writeln!(out, "if (Test-Path -LiteralPath {}) {{", ps_quote(path)).unwrap();
+        if let Some(size) = info.size() {
+            writeln!(out, "    Test-FileSize {} {}", ps_quote(path), size).unwrap();
+        }
+        if let Some(sha256) = info.sha256() {
+            writeln!(out, "    Test-Sha256 {} {}", ps_quote(path), ps_quote(sha256)).unwrap();
+        }
+        writeln!(out, "}}").unwrap();
+    }
+
+    for (archive, target) in sorted_extract(templ) {
+        out.push('\n');
+        // Optional archives are only extracted if they were downloaded.
+        let indent = if is_optional(templ, archive) {
+            writeln!(out, "if (Test-Path -LiteralPath {}) {{", ps_quote(archive)).unwrap();
+            "    "
+        } else {
+            ""
+        };
+        match target {
+            _ if is_tar(archive) => {
+                writeln!(out, "{}# Not exported: {} (tar archive)", indent, one_line(archive))
+                    .unwrap();
+            },
+            ExtractTarget::Directory(dir) if strips_components(templ, archive) => {
+                writeln!(out, "{}# Not exported: {} to {} with strip_components",
+                         indent, one_line(archive), one_line(dir)).unwrap();
+            },
+            ExtractTarget::Directory(dir) => {
+                writeln!(out, "{}Expand-ArchiveTo {} {}", indent, ps_quote(archive),
+                         ps_quote(dir)).unwrap();
+            },
+            ExtractTarget::Mapping(files) => {
+                for (name, dest) in sorted_files(files) {
+                    let path = match dest.path() {
+                        Some(path) => path,
+                        None => {
+                            writeln!(out, "{}# Not exported: nested archive {}", indent,
+                                     one_line(name)).unwrap();
+                            continue;
+                        }
+                    };
+                    if is_pattern(name) {
+                        writeln!(out, "{}# Not exported: files matching {} to {}",
+                                 indent, one_line(name), one_line(path)).unwrap();
+                        continue;
+                    }
+                    writeln!(out, "{}Expand-ArchiveFile {} {} {}", indent, ps_quote(archive),
+                             ps_quote(name), ps_quote(path)).unwrap();
+                }
+            }
+        }
+        if deletes_archive(templ, archive, &target) && !is_tar(archive) {
+            writeln!(out, "{}Remove-Item -LiteralPath {}", indent, ps_quote(archive)).unwrap();
+        }
+        if !indent.is_empty() {
+            writeln!(out, "}}").unwrap();
+        }
+    }
+    out
+}
+
+/// Quotes a string as a PowerShell string literal. PowerShell also treats
+/// typographic single quotes as quotes, so they are doubled as well.
+fn ps_quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('\'');
+    for c in s.chars() {
+        if PS_QUOTES.contains(&c) {
+            quoted.push(c);
+        }
+        quoted.push(c);
+    }
+    quoted.push('\'');
+    quoted
+}
+
+/// The characters that PowerShell accepts as single quotes.
+const PS_QUOTES: [char; 5] = ['\'', '\u{2018}', '\u{2019}', '\u{201a}', '\u{201b}'];
+
+/// Escapes the characters that could start a new line in a string that is
+/// written on a single line, such as in a comment or an aria2 option. Control
+/// characters and Unicode line separators are written as escape sequences.
+fn one_line(s: &str) -> Cow<'_, str> {
+    let is_break = |c: char| c.is_control() || c == '\u{2028}' || c == '\u{2029}';
+    if !s.chars().any(is_break) {
+        return Cow::Borrowed(s);
+    }
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if is_break(c) {
+            escaped.extend(c.escape_default());
+        } else {
+            escaped.push(c);
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Strings that would be interpreted by a shell if they were not quoted.
+    const SPECIAL: [&str; 6] = [
+        "it's", "$HOME ${x}", "`id` $(id)", "line\nbreak", "'';\"\\", "*?[a] ~",
+    ];
+
+    #[test]
+    fn sh_quoting() {
+        assert_eq!(sh_quote("plain"), "'plain'");
+        assert_eq!(sh_quote("it's"), r"'it'\''s'");
+        assert_eq!(sh_quote("$x `y`"), "'$x `y`'");
+        assert_eq!(sh_quote("a\nb"), "'a\nb'");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sh_quoting_round_trip() {
+        for s in &SPECIAL {
+            let output = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(format!("printf %s {}", sh_quote(s)))
+                .output()
+                .unwrap();
+            assert_eq!(String::from_utf8(output.stdout).unwrap(), *s);
+        }
+    }
+
+    #[test]
+    fn ps_quoting() {
+        assert_eq!(ps_quote("plain"), "'plain'");
+        assert_eq!(ps_quote("it's"), "'it''s'");
+        assert_eq!(ps_quote("it\u{2019}s"), "'it\u{2019}\u{2019}s'");
+        // Variables, subexpressions, and backticks are not expanded in single
+        // quotes, and line breaks are kept as they are.
+        assert_eq!(ps_quote("$x $(y) `n"), "'$x $(y) `n'");
+        assert_eq!(ps_quote("a\nb"), "'a\nb'");
+        for s in &SPECIAL {
+            let quoted = ps_quote(s);
+            let inner = &quoted[1..quoted.len() - 1];
+            // Every quote inside of the literal is doubled.
+            assert!(!inner.replace("''", "").contains('\''), "{}", quoted);
+        }
+    }
+
+    #[test]
+    fn one_line_escapes() {
+        assert_eq!(one_line("plain"), "plain");
+        assert_eq!(one_line("a\nb\rc"), "a\\nb\\rc");
+        assert_eq!(one_line("a\u{2028}b"), "a\\u{2028}b");
+    }
+
+    #[test]
+    fn unzip_escapes() {
+        assert_eq!(unzip_escape("a*b?[c].txt"), "a[*]b[?][[]c].txt");
+    }
+}
@@ -0,0 +1,1476 @@
+use glob::{MatchOptions, Pattern};
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::errors;
+use crate::tar::{TarEntry, TarHandler, TarKind};
+use crate::template::{Destination, ExtractInfo, ExtractTarget, FileMode, StripComponents};
+
+/// The prefix of mapping keys that are regular expressions.
+const REGEX_PREFIX: &str = "regex:";
+
+/// How a key in a mapping selects entries in an archive.
+#[derive(Debug)]
+enum Selector {
+    /// Selects the entry with exactly this name.
+    Exact(String),
+    /// Selects the entries matching the pattern. `base` is the directory
+    /// containing the first wildcard, which is removed from the names of the
+    /// entries when they are extracted to a directory.
+    Glob { pattern: Pattern, base: PathBuf },
+    /// Selects the entries whose whole names match the regular expression.
+    Regex(Regex)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// A location that an archive entry is extracted to. `dir` is written in the
+/// template and is used as given, while `path` depends on the archive, and is
+/// only ever written inside of `dir`.
+pub struct EntryPath {
+    pub dir: PathBuf,
+    pub path: PathBuf
+}
+
+impl EntryPath {
+    /// Splits a destination written in the template into its directory and
+    /// file name.
+    fn split(dest: &str) -> Self {
+        let index = dest.rfind('/').map_or(0, |i| i + 1);
+        EntryPath { dir: PathBuf::from(&dest[..index]), path: PathBuf::from(&dest[index..]) }
+    }
+
+    /// Returns the full path of the entry.
+    pub fn joined(&self) -> PathBuf {
+        self.dir.join(&self.path)
+    }
+}
+
+#[derive(Debug)]
+/// A compiled mapping from entries in an archive to the locations to extract
+/// them to. Keys are either exact entry names, glob patterns (if they contain
+/// `*`, `?`, or `[`), or regular expressions (if they start with `regex:`).
+/// Destinations ending in `/` are directories; otherwise, destinations of
+/// regular expressions may refer to capture groups with `$1` or `$name`
+/// (braces would be read as template variables).
+/// Nested archives, which are always given by name, have no destination.
+pub struct Mapping {
+    rules: Vec<(Selector, Destination)>
+}
+
+impl Mapping {
+    /// Compiles the mapping, returning an error if a pattern is invalid.
+    pub fn new(files: &HashMap<String, Destination>) -> errors::Result<Self> {
+        let mut keys: Vec<_> = files.keys().collect();
+        keys.sort();
+        let mut rules = Vec::with_capacity(keys.len());
+        for key in keys {
+            let selector = if let Some(re) = key.strip_prefix(REGEX_PREFIX) {
+                // Compile the expression by itself first so that errors refer
+                // to the expression as written.
+                Regex::new(re).map_err(|err| errors::invalid_pattern(key, &err.to_string()))?;
+                let anchored = Regex::new(&format!("^(?:{})$", re))
+                    .map_err(|err| errors::invalid_pattern(key, &err.to_string()))?;
+                Selector::Regex(anchored)
+            } else if is_glob(key) {
+                let pattern = Pattern::new(key)
+                    .map_err(|err| errors::invalid_pattern(key, err.msg))?;
+                let base = Path::new(key).components()
+                    .take_while(|c| !is_glob(&c.as_os_str().to_string_lossy()))
+                    .collect();
+                Selector::Glob { pattern, base }
+            } else {
+                Selector::Exact(key.clone())
+            };
+            rules.push((selector, files[key].clone()));
+        }
+        Ok(Mapping { rules })
+    }
+
+    /// Returns the locations that the archive entry with the given name should
+    /// be extracted to, along with the destinations that select them. An entry
+    /// is extracted once for each key that selects it. Nested archives are not
+    /// included. The parts of the locations that come from the entry name,
+    /// such as captures, are kept apart from the directories in the mapping.
+    pub fn destinations(&self, name: &str) -> Vec<(EntryPath, &Destination)> {
+        let opts = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        };
+        let is_dir_entry = name.ends_with('/');
+        let mut dests = Vec::new();
+        for (selector, target) in &self.rules {
+            let dest = match target.path() {
+                Some(dest) => dest,
+                None => continue
+            };
+            let path = match selector {
+                Selector::Exact(key) => {
+                    if key != name {
+                        continue;
+                    }
+                    EntryPath::split(dest)
+                },
+                Selector::Glob { pattern, base } => {
+                    if is_dir_entry || !pattern.matches_with(name, opts) {
+                        continue;
+                    }
+                    if dest.ends_with('/') {
+                        let rel = Path::new(name).strip_prefix(base).unwrap_or_else(|_| Path::new(name));
+                        EntryPath { dir: PathBuf::from(dest), path: rel.to_owned() }
+                    } else {
+                        EntryPath::split(dest)
+                    }
+                },
+                Selector::Regex(re) => {
+                    let caps = match re.captures(name) {
+                        Some(caps) if !is_dir_entry => caps,
+                        _ => continue
+                    };
+                    if dest.ends_with('/') {
+                        let file_name = Path::new(name).file_name().unwrap_or_default();
+                        EntryPath { dir: PathBuf::from(dest), path: PathBuf::from(file_name) }
+                    } else {
+                        // Everything from the directory containing the first
+                        // capture on may come from the entry name.
+                        let first = dest.find('$').unwrap_or(dest.len());
+                        let index = dest[..first].rfind('/').map_or(0, |i| i + 1);
+                        let mut expanded = String::new();
+                        caps.expand(&dest[index..], &mut expanded);
+                        EntryPath { dir: PathBuf::from(&dest[..index]), path: PathBuf::from(expanded) }
+                    }
+                }
+            };
+            dests.push((path, target));
+        }
+        dests
+    }
+
+    /// Returns how to extract the entry with the given name, if it is a
+    /// nested archive.
+    pub fn nested(&self, name: &str) -> Option<&ExtractInfo> {
+        self.rules.iter()
+            .find(|(selector, _)| matches!(selector, Selector::Exact(key) if key == name))
+            .and_then(|(_, dest)| dest.nested())
+    }
+}
+
+/// Where the entries of an archive that is read as a stream are extracted to.
+enum StreamTarget {
+    /// All entries are extracted to the directory, after removing leading
+    /// components. In automatic mode, the top-level directory is taken from
+    /// the first entry, since later entries are not known yet.
+    Directory { dir: PathBuf, strip: Option<StripComponents>, top: Option<Option<OsString>> },
+    Mapping(Mapping)
+}
+
+/// Extracts the entries of a tar archive as it is parsed, so that the archive
+/// does not have to be stored. `report` is called with a message for each
+/// entry that is extracted or skipped.
+pub struct StreamExtractor {
+    archive: String,
+    writer: EntryWriter,
+    target: StreamTarget,
+    /// The destinations that have been extracted to.
+    dests: HashSet<PathBuf>,
+    /// The destinations of the current entry.
+    current: Vec<(PendingEntry, Option<FileMode>)>,
+    /// The nested archive that the current entry is stored to, if any.
+    current_nested: Option<NestedArchive>,
+    /// The nested archives that have been stored.
+    nested: Vec<NestedArchive>,
+    limits: LimitTracker,
+    report: Box<dyn FnMut(String) + Send>
+}
+
+impl StreamExtractor {
+    /// Creates an extractor for the archive, which writes the entries with
+    /// the writer. Extraction fails once one of the limits is exceeded.
+    pub fn new(archive: &str, writer: EntryWriter, info: &ExtractInfo, limits: LimitTracker,
+               report: Box<dyn FnMut(String) + Send>) -> errors::Result<Self> {
+        let target = match info.target() {
+            ExtractTarget::Directory(dir) => StreamTarget::Directory {
+                dir: PathBuf::from(dir),
+                strip: info.strip_components(),
+                top: None
+            },
+            ExtractTarget::Mapping(files) => StreamTarget::Mapping(Mapping::new(files)?)
+        };
+        Ok(StreamExtractor {
+            archive: archive.to_owned(),
+            writer,
+            target,
+            dests: HashSet::new(),
+            current: Vec::new(),
+            current_nested: None,
+            nested: Vec::new(),
+            limits,
+            report
+        })
+    }
+
+    /// Returns the locations that the entry should be extracted to.
+    fn destinations(&mut self, entry: &TarEntry) -> errors::Result<Vec<(EntryPath, Option<FileMode>)>> {
+        let name = &entry.name;
+        let is_dir = entry.kind == TarKind::Directory || name.ends_with('/');
+        match &mut self.target {
+            StreamTarget::Directory { dir, strip, top } => {
+                let count = match strip {
+                    None => 0,
+                    Some(StripComponents::Count(count)) => *count,
+                    Some(StripComponents::Auto) => {
+                        let mut parts = name_components(name);
+                        let first = parts.next().map(|p| p.to_owned());
+                        if top.is_none() && first.is_some() {
+                            let nested = is_dir || parts.next().is_some();
+                            *top = Some(if nested { first.clone() } else { None });
+                        }
+                        match top {
+                            Some(Some(top)) if first.as_ref() == Some(top) => 1,
+                            Some(Some(top)) => {
+                                return Err(errors::not_in_top_dir(name, &top.to_string_lossy()));
+                            },
+                            _ => 0
+                        }
+                    }
+                };
+                if is_dir {
+                    return Ok(Vec::new());
+                }
+                Ok(strip_name(name, count)
+                    .map(|path| (EntryPath { dir: dir.clone(), path }, None))
+                    .into_iter()
+                    .collect())
+            },
+            StreamTarget::Mapping(mapping) => {
+                if is_dir {
+                    return Ok(Vec::new());
+                }
+                Ok(mapping.destinations(name).into_iter()
+                    .map(|(path, dest)| (path, dest.file_mode()))
+                    .collect())
+            }
+        }
+    }
+
+    fn start_entry(&mut self, entry: &TarEntry) -> errors::Result<()> {
+        let dests = self.destinations(entry)?;
+        let is_supported = entry.kind == TarKind::File || matches!(entry.kind, TarKind::Symlink(_));
+        if !is_supported {
+            return Ok(());
+        }
+        let meta = EntryMeta::from_tar(entry);
+        for (dest, mode) in dests {
+            let joined = dest.joined();
+            if !self.dests.insert(joined.clone()) {
+                return Err(errors::duplicate_destination(&self.archive, &joined));
+            }
+            match self.writer.begin(&dest, &meta)? {
+                Some(mut pending) => {
+                    if let TarKind::Symlink(target) = &entry.kind {
+                        io::Write::write_all(&mut pending, target.as_bytes())?;
+                    }
+                    (self.report)(format!("Extracting {} to {}", entry.name, joined.display()));
+                    let mode = if meta.kind == EntryKind::File { mode } else { None };
+                    self.current.push((pending, mode));
+                },
+                None => (self.report)(format!("{} already exists, skipping", joined.display()))
+            }
+        }
+        if let (StreamTarget::Mapping(mapping), TarKind::File) = (&self.target, &entry.kind) {
+            if let Some(info) = mapping.nested(&entry.name) {
+                self.current_nested = Some(NestedArchive::create(&entry.name, info, &self.limits)?);
+            }
+        }
+        let copies = self.current.len() + self.current_nested.iter().count();
+        if copies > 0 {
+            self.limits.start_entry(&entry.name, None, copies as u64)?;
+        }
+        Ok(())
+    }
+
+    fn end_entry(&mut self) -> errors::Result<()> {
+        if let Some(mut nested) = self.current_nested.take() {
+            nested.finish()?;
+            self.nested.push(nested);
+        }
+        for (pending, mode) in std::mem::take(&mut self.current) {
+            // The entry may be staged, so its mode is set where it is written.
+            let dest = pending.real_dest.clone();
+            pending.finish(&self.writer)?;
+            if let Some(mode) = mode {
+                set_file_mode(&dest, mode)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the extracted entries into place if the writer is staged.
+    pub fn commit(&mut self) -> errors::Result<()> {
+        self.writer.commit()
+    }
+
+    /// Returns the nested archives that were stored while the archive was
+    /// extracted, which still have to be extracted.
+    pub fn into_nested(self) -> Vec<NestedArchive> {
+        self.nested
+    }
+}
+
+impl TarHandler for StreamExtractor {
+    fn start(&mut self, entry: &TarEntry) -> io::Result<()> {
+        self.start_entry(entry).map_err(to_io)
+    }
+
+    fn data(&mut self, buf: &[u8]) -> io::Result<()> {
+        if self.current.is_empty() && self.current_nested.is_none() {
+            return Ok(());
+        }
+        self.limits.add(buf.len() as u64).map_err(to_io)?;
+        for (pending, _) in &mut self.current {
+            io::Write::write_all(pending, buf)?;
+        }
+        if let Some(nested) = &mut self.current_nested {
+            io::Write::write_all(nested, buf)?;
+        }
+        Ok(())
+    }
+
+    fn end(&mut self) -> io::Result<()> {
+        self.end_entry().map_err(to_io)
+    }
+}
+
+/// The number of nested archives that have been stored by this process, which
+/// gives each one a temporary file of its own.
+static NESTED_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// An archive inside of another archive, which is extracted once the archive
+/// containing it has been read. Its contents are stored in a hidden temporary
+/// file in the directory that it is extracted to, which is deleted when it is
+/// dropped.
+pub struct NestedArchive {
+    /// The name of the entry in the archive containing it.
+    pub name: String,
+    /// The name used to refer to the archive, such as `tools.tar.gz in
+    /// sdk.zip`.
+    pub label: String,
+    pub info: ExtractInfo,
+    /// Counts what is extracted from the archive against the limits of the
+    /// archive containing it.
+    limits: LimitTracker,
+    path: PathBuf,
+    /// The temporary file, while it is being written.
+    file: Option<fs::File>
+}
+
+impl NestedArchive {
+    /// Creates the temporary file for the entry `name` in the archive whose
+    /// extraction is counted by `parent`. The nested archive is extracted as
+    /// described by `info`.
+    pub fn create(name: &str, info: &ExtractInfo, parent: &LimitTracker) -> io::Result<Self> {
+        let dir = match info.target() {
+            ExtractTarget::Directory(dir) => Path::new(dir),
+            ExtractTarget::Mapping(_) => Path::new(".")
+        };
+        fs::create_dir_all(dir)?;
+        let count = NESTED_COUNT.fetch_add(1, Ordering::SeqCst);
+        let path = dir.join(format!(".tempget-nested-{}-{}.part", std::process::id(), count));
+        let file = fs::OpenOptions::new().write(true).create_new(true).open(&path)?;
+        let label = crate::template::nested_name(name, &parent.archive);
+        Ok(NestedArchive {
+            name: name.to_owned(),
+            limits: parent.nested(&label),
+            label,
+            info: info.clone(),
+            path,
+            file: Some(file)
+        })
+    }
+
+    /// Finishes writing the archive.
+    pub fn finish(&mut self) -> io::Result<()> {
+        match self.file.take() {
+            Some(mut file) => io::Write::flush(&mut file),
+            None => Ok(())
+        }
+    }
+
+    /// Returns a tracker that counts what is extracted from the archive
+    /// towards the totals of the archive containing it.
+    pub fn limits(&self) -> LimitTracker {
+        self.limits.nested(&self.label)
+    }
+
+    /// Opens the archive for reading. It must have been finished.
+    pub fn open(&self) -> io::Result<fs::File> {
+        fs::File::open(&self.path)
+    }
+}
+
+impl io::Write for NestedArchive {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.as_mut().expect("the nested archive is finished").write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for NestedArchive {
+    fn drop(&mut self) {
+        drop(self.file.take());
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Entries that extract to fewer bytes than this are not checked against the
+/// compression ratio limit, since small files can be compressed very well.
+const RATIO_MIN_SIZE: u64 = 1 << 20;
+
+#[derive(Debug, Clone, Copy, Default)]
+/// Limits on how much is extracted from an archive, which protect against
+/// archives that expand to fill the disk. Limits that are `None` are not
+/// checked.
+pub struct ExtractLimits {
+    /// The total number of bytes written.
+    pub max_size: Option<u64>,
+    /// The number of bytes written for a single entry.
+    pub max_entry_size: Option<u64>,
+    /// The number of files and symlinks written.
+    pub max_entries: Option<u64>,
+    /// The ratio of the size of an entry to its compressed size. This only
+    /// applies to zip archives, since the entries of tar archives are not
+    /// compressed separately.
+    pub max_ratio: Option<u64>
+}
+
+/// The totals counted by a `LimitTracker`, which are shared with the trackers
+/// of the archives nested inside of its archive.
+#[derive(Default)]
+struct LimitTotals {
+    /// The number of bytes written so far.
+    bytes: AtomicU64,
+    /// The number of entries started so far.
+    entries: AtomicU64
+}
+
+/// Counts what is extracted from an archive, returning an error as soon as a
+/// limit is exceeded. The sizes given in the archive are never trusted; only
+/// the bytes that are actually extracted are counted. Nested archives are
+/// counted towards the totals of the archive containing them.
+pub struct LimitTracker {
+    archive: String,
+    limits: ExtractLimits,
+    totals: Arc<LimitTotals>,
+    /// The name of the current entry.
+    name: String,
+    /// The number of bytes extracted from the current entry.
+    entry_size: u64,
+    /// The compressed size of the current entry, if it is known.
+    compressed: Option<u64>,
+    /// The number of destinations that the current entry is written to.
+    copies: u64
+}
+
+impl LimitTracker {
+    pub fn new(archive: &str, limits: ExtractLimits) -> Self {
+        LimitTracker {
+            archive: archive.to_owned(),
+            limits,
+            totals: Arc::new(LimitTotals::default()),
+            name: String::new(),
+            entry_size: 0,
+            compressed: None,
+            copies: 0
+        }
+    }
+
+    /// Returns the name of the archive, which is used in errors.
+    pub fn archive(&self) -> &str {
+        &self.archive
+    }
+
+    /// Returns a tracker for an archive nested inside of this one, which
+    /// shares the total size and number of entries with this tracker.
+    pub fn nested(&self, archive: &str) -> Self {
+        LimitTracker {
+            archive: archive.to_owned(),
+            totals: self.totals.clone(),
+            ..LimitTracker::new(archive, self.limits)
+        }
+    }
+
+    /// Starts counting an entry that is written to `copies` destinations.
+    pub fn start_entry(&mut self, name: &str, compressed: Option<u64>, copies: u64)
+                       -> errors::Result<()> {
+        let entries = self.totals.entries.fetch_add(copies, Ordering::SeqCst) + copies;
+        if let Some(max) = self.limits.max_entries.filter(|&max| entries > max) {
+            return Err(errors::limit_exceeded(&self.archive,
+                                              &format!("it has more than {} entries", max)));
+        }
+        self.name = name.to_owned();
+        self.entry_size = 0;
+        self.compressed = compressed;
+        self.copies = copies;
+        Ok(())
+    }
+
+    /// Counts bytes extracted from the current entry.
+    pub fn add(&mut self, len: u64) -> errors::Result<()> {
+        self.entry_size += len;
+        let added = len * self.copies;
+        let total = self.totals.bytes.fetch_add(added, Ordering::SeqCst) + added;
+        let limits = &self.limits;
+        let reason = if let Some(max) = limits.max_size.filter(|&max| total > max) {
+            format!("it extracts to more than {} bytes", max)
+        } else if let Some(max) = limits.max_entry_size.filter(|&max| self.entry_size > max) {
+            format!("{} is larger than {} bytes", self.name, max)
+        } else {
+            match (limits.max_ratio, self.compressed) {
+                (Some(max), Some(compressed))
+                    if self.entry_size >= RATIO_MIN_SIZE
+                        && self.entry_size / compressed.max(1) > max => {
+                    format!("{} is compressed more than {} times", self.name, max)
+                },
+                _ => return Ok(())
+            }
+        };
+        Err(errors::limit_exceeded(&self.archive, &reason))
+    }
+}
+
+/// A reader that counts the bytes read from an entry with a `LimitTracker`,
+/// failing once a limit is exceeded.
+pub struct LimitedReader<'a, R> {
+    inner: R,
+    tracker: &'a mut LimitTracker
+}
+
+impl<'a, R: Read> LimitedReader<'a, R> {
+    /// Starts a new entry in the tracker, which is read from `inner`.
+    pub fn new(inner: R, tracker: &'a mut LimitTracker, name: &str, compressed: Option<u64>)
+               -> errors::Result<Self> {
+        tracker.start_entry(name, compressed, 1)?;
+        Ok(LimitedReader { inner, tracker })
+    }
+}
+
+impl<'a, R: Read> Read for LimitedReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.tracker.add(n as u64).map_err(to_io)?;
+        Ok(n)
+    }
+}
+
+/// Converts an error to an `io::Error`, so that it can be returned from a
+/// writer.
+fn to_io(err: errors::Error) -> io::Error {
+    io::Error::other(err.to_string())
+}
+
+/// Returns the path of the file that records that an archive has been
+/// extracted, which is stored next to the archive.
+fn marker_path(archive: &str) -> PathBuf {
+    let path = Path::new(archive);
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".tempget-extracted");
+    path.with_file_name(name)
+}
+
+/// Returns the contents of the marker of an archive extracted as described by
+/// `info`, so that changing the `extract` entry invalidates the marker. The
+/// keys of JSON objects are sorted, which keeps the contents stable.
+fn marker_contents(info: &ExtractInfo) -> String {
+    serde_json::to_value(info).map(|value| value.to_string()).unwrap_or_default()
+}
+
+/// Records that the archive has been extracted as described by `info`. This
+/// is used for archives that are not kept after extracting them.
+pub fn mark_extracted(archive: &str, info: &ExtractInfo) -> io::Result<()> {
+    fs::write(marker_path(archive), marker_contents(info))
+}
+
+/// Returns true if the archive has been extracted already: it was marked with
+/// `mark_extracted` using the same `info`, and the directory or files that it
+/// was extracted to still exist. This is used to skip archives that are not
+/// kept after extracting them.
+pub fn is_extracted(archive: &str, info: &ExtractInfo) -> bool {
+    let marked = fs::read_to_string(marker_path(archive))
+        .map(|contents| contents == marker_contents(info))
+        .unwrap_or(false);
+    marked && targets_exist(info)
+}
+
+/// Returns true if the directory that an archive is extracted to exists, or
+/// every file in the mapping exists. Only files given by name are checked,
+/// along with the targets of nested archives.
+fn targets_exist(info: &ExtractInfo) -> bool {
+    match info.target() {
+        ExtractTarget::Directory(dir) => Path::new(dir).is_dir(),
+        ExtractTarget::Mapping(files) => files.iter().all(|(name, dest)| {
+            match (dest.path(), dest.nested()) {
+                (Some(path), _) if !is_pattern(name) => Path::new(path).exists(),
+                (None, Some(nested)) => targets_exist(nested),
+                _ => true
+            }
+        })
+    }
+}
+
+/// Returns the number of leading components to remove from the names of the
+/// entries in an archive. In automatic mode, the top-level directory is
+/// removed if every entry is inside of the same one.
+pub fn strip_count<'a, I>(strip: Option<StripComponents>, names: I) -> usize
+    where I: IntoIterator<Item = &'a str> {
+    match strip {
+        None => 0,
+        Some(StripComponents::Count(count)) => count,
+        Some(StripComponents::Auto) => {
+            let mut top = None;
+            for name in names {
+                let mut parts = name_components(name);
+                let first = match parts.next() {
+                    Some(first) => first,
+                    None => continue
+                };
+                // A file at the top level cannot be inside of a directory.
+                if parts.next().is_none() && !name.ends_with('/') {
+                    return 0;
+                }
+                match top {
+                    None => top = Some(first),
+                    Some(top) if top == first => {},
+                    Some(_) => return 0
+                }
+            }
+            if top.is_some() { 1 } else { 0 }
+        }
+    }
+}
+
+/// Removes `count` leading components from the name of an archive entry.
+/// Returns `None` if nothing is left.
+pub fn strip_name(name: &str, count: usize) -> Option<PathBuf> {
+    let stripped: PathBuf = name_components(name).skip(count).collect();
+    if stripped.as_os_str().is_empty() {
+        None
+    } else {
+        Some(stripped)
+    }
+}
+
+/// Returns the components of an archive entry name, ignoring `.`.
+fn name_components(name: &str) -> impl Iterator<Item = &std::ffi::OsStr> {
+    Path::new(name).components()
+        .filter(|c| *c != Component::CurDir)
+        .map(|c| c.as_os_str())
+}
+
+/// The maximum number of symlinks followed while resolving a path.
+const MAX_LINK_DEPTH: usize = 40;
+
+/// The bits of a Unix mode that give the type of the file.
+const MODE_TYPE_MASK: u32 = 0o170_000;
+
+/// The file type bits of a symlink.
+const MODE_SYMLINK: u32 = 0o120_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The kinds of archive entries that can be extracted.
+pub enum EntryKind {
+    File,
+    /// A symlink, whose target is the contents of the entry.
+    Symlink
+}
+
+#[derive(Debug, Clone)]
+/// The metadata of an archive entry that is restored when it is extracted.
+pub struct EntryMeta {
+    pub kind: EntryKind,
+    /// The Unix permission bits of the entry, if the archive stores them.
+    pub mode: Option<u32>,
+    /// The modification time of the entry.
+    pub mtime: Option<SystemTime>
+}
+
+impl EntryMeta {
+    /// Reads the metadata of an entry in a zip archive. Zip archives store
+    /// modification times without a time zone, so they are treated as UTC.
+    pub fn from_zip(entry: &zip::read::ZipFile) -> Self {
+        let mode = entry.unix_mode();
+        let kind = match mode {
+            Some(mode) if mode & MODE_TYPE_MASK == MODE_SYMLINK => EntryKind::Symlink,
+            _ => EntryKind::File
+        };
+        let time = entry.last_modified();
+        let days = days_from_civil(i64::from(time.year()), time.month().into(), time.day().into());
+        let secs = days * 86_400 + i64::from(time.hour()) * 3600
+            + i64::from(time.minute()) * 60 + i64::from(time.second());
+        let mtime = if secs >= 0 {
+            Some(UNIX_EPOCH + Duration::from_secs(secs as u64))
+        } else {
+            None
+        };
+        // Archives created without permissions store a mode of zero.
+        let mode = mode.map(|m| m & 0o7777).filter(|&m| m != 0);
+        EntryMeta { kind, mode, mtime }
+    }
+}
+
+impl EntryMeta {
+    /// Returns the metadata of an entry in a tar archive.
+    pub fn from_tar(entry: &TarEntry) -> Self {
+        let kind = match entry.kind {
+            TarKind::Symlink(_) => EntryKind::Symlink,
+            _ => EntryKind::File
+        };
+        EntryMeta {
+            kind,
+            mode: Some(entry.mode).filter(|&m| m != 0),
+            mtime: Some(UNIX_EPOCH + Duration::from_secs(entry.mtime))
+        }
+    }
+}
+
+/// Returns the number of days between 1970-01-01 and the given date.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // See http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The number of staging directories that have been created by this process,
+/// which gives each one a name of its own.
+static STAGE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Writes archive entries into directories, restoring their permissions,
+/// symlinks, and modification times. Nothing is ever written outside of the
+/// directory of an entry: its path is resolved by following the symlinks that
+/// already exist, and symlinks may only point to locations in the directory.
+///
+/// A staged writer writes entries into a hidden directory inside of each
+/// directory instead, and only moves them into place once `commit` is called.
+/// The staging directories are deleted when the writer is dropped.
+pub struct EntryWriter {
+    /// The staging directories of the directories that entries have been
+    /// written to, by the canonical paths of the directories, if entries are
+    /// staged.
+    stages: Option<HashMap<PathBuf, PathBuf>>,
+    /// The permission bits that are cleared from the modes of entries.
+    umask: u32
+}
+
+impl EntryWriter {
+    /// Creates a writer that writes entries into place right away.
+    pub fn new(umask: u32) -> Self {
+        EntryWriter { stages: None, umask }
+    }
+
+    /// Creates a writer that stages entries until they are committed.
+    pub fn staged(umask: u32) -> Self {
+        EntryWriter { stages: Some(HashMap::new()), umask }
+    }
+
+    /// Writes an entry to `dest`. Returns false without writing anything if
+    /// `dest` already exists.
+    pub fn write<R: Read>(&mut self, dest: &EntryPath, meta: &EntryMeta, mut contents: R)
+                          -> errors::Result<bool> {
+        match self.begin(dest, meta)? {
+            Some(mut pending) => {
+                io::copy(&mut contents, &mut pending)?;
+                pending.finish(self)?;
+                Ok(true)
+            },
+            None => Ok(false)
+        }
+    }
+
+    /// Starts writing an entry to `dest`, creating its directory if it does
+    /// not exist. The contents of the entry are written to the returned
+    /// `PendingEntry`, which must then be finished. Returns `None` if `dest`
+    /// already exists.
+    pub fn begin(&mut self, dest: &EntryPath, meta: &EntryMeta)
+                 -> errors::Result<Option<PendingEntry>> {
+        let name = match dest.path.components().next_back() {
+            Some(Component::Normal(name)) => name,
+            _ => return Err(errors::unsafe_path(&dest.joined(), &dest.dir))
+        };
+        let parent = dest.path.parent().unwrap_or_else(|| Path::new(""));
+        let dir = if dest.dir.as_os_str().is_empty() { Path::new(".") } else { &dest.dir };
+        fs::create_dir_all(dir)?;
+        let target = fs::canonicalize(dir)?;
+        let root = self.root(&target)?;
+        let real_parent = resolve_in(&root, root.clone(), parent, 0)?
+            .ok_or_else(|| errors::unsafe_path(&dest.joined(), &dest.dir))?;
+        let real_dest = real_parent.join(name);
+        if fs::symlink_metadata(&real_dest).is_ok() {
+            return Ok(None);
+        }
+        if root != target {
+            // Entries that exist in the directory itself are skipped as well.
+            let existing = resolve_in(&target, target.clone(), parent, 0)?
+                .map(|p| p.join(name));
+            if existing.is_some_and(|p| fs::symlink_metadata(p).is_ok()) {
+                return Ok(None);
+            }
+        }
+        fs::create_dir_all(&real_parent)?;
+
+        let contents = match meta.kind {
+            EntryKind::Symlink => PendingContents::Symlink(Vec::new()),
+            EntryKind::File => {
+                // Write to a temporary file, so that an entry that is cut off
+                // is never mistaken for a complete file.
+                let mut temp_name = name.to_owned();
+                temp_name.push(".tempget-part");
+                let temp = real_parent.join(temp_name);
+                let file = fs::File::create(&temp)?;
+                PendingContents::File(file, temp)
+            }
+        };
+        Ok(Some(PendingEntry {
+            dest: dest.clone(),
+            root,
+            real_dest,
+            meta: meta.clone(),
+            contents: Some(contents)
+        }))
+    }
+
+    /// Returns the canonical path of the directory that entries for `target`
+    /// are written to, which is its staging directory if entries are staged.
+    fn root(&mut self, target: &Path) -> io::Result<PathBuf> {
+        let stages = match &mut self.stages {
+            Some(stages) => stages,
+            None => return Ok(target.to_owned())
+        };
+        if let Some(stage) = stages.get(target) {
+            return Ok(stage.clone());
+        }
+        let count = STAGE_COUNT.fetch_add(1, Ordering::SeqCst);
+        let stage = target.join(format!(".tempget-stage-{}-{}", std::process::id(), count));
+        fs::create_dir(&stage)?;
+        stages.insert(target.to_owned(), stage.clone());
+        Ok(stage)
+    }
+
+    /// Moves the staged entries into their directories, skipping the ones
+    /// that already exist there. Symlinks are checked again, since the
+    /// directories may contain different symlinks than the staging
+    /// directories. Does nothing if the writer is not staged.
+    pub fn commit(&mut self) -> errors::Result<()> {
+        let stages = match &mut self.stages {
+            Some(stages) => stages,
+            None => return Ok(())
+        };
+        let mut sorted: Vec<_> = stages.iter().collect();
+        sorted.sort();
+        for (target, stage) in sorted {
+            merge(target, stage, Path::new(""))?;
+        }
+        for (_, stage) in stages.drain() {
+            fs::remove_dir_all(stage)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for EntryWriter {
+    fn drop(&mut self) {
+        for stage in self.stages.iter().flat_map(|stages| stages.values()) {
+            let _ = fs::remove_dir_all(stage);
+        }
+    }
+}
+
+/// Moves the entries in the directory `rel` of the staging directory `stage`
+/// into `target`.
+fn merge(target: &Path, stage: &Path, rel: &Path) -> errors::Result<()> {
+    for entry in fs::read_dir(stage.join(rel))? {
+        let entry = entry?;
+        let rel = rel.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            merge(target, stage, &rel)?;
+            continue;
+        }
+        let dest = target.join(&rel);
+        let parent = rel.parent().unwrap_or_else(|| Path::new(""));
+        let real_parent = resolve_in(target, target.to_owned(), parent, 0)?
+            .ok_or_else(|| errors::unsafe_path(&dest, target))?;
+        let real_dest = real_parent.join(entry.file_name());
+        if fs::symlink_metadata(&real_dest).is_ok() {
+            continue;
+        }
+        if file_type.is_symlink() {
+            let link = fs::read_link(entry.path())?;
+            if resolve_in(target, real_parent.clone(), &link, 0)?.is_none() {
+                return Err(errors::unsafe_link(&dest, &link, target));
+            }
+        }
+        fs::create_dir_all(&real_parent)?;
+        fs::rename(entry.path(), &real_dest)?;
+    }
+    Ok(())
+}
+
+/// Resolves `path` relative to `base`, which must be a canonical path inside
+/// of `root`, by following the symlinks that exist. Returns `None` if the path
+/// leads outside of `root`, or if it cannot be resolved reliably because it
+/// goes up from a directory that does not exist yet.
+fn resolve_in(root: &Path, base: PathBuf, path: &Path, depth: usize) -> io::Result<Option<PathBuf>> {
+    if depth > MAX_LINK_DEPTH {
+        return Ok(None);
+    }
+    let mut current = base;
+    for component in path.components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir => return Ok(None),
+            Component::CurDir => {},
+            Component::ParentDir => {
+                if !current.exists() || !current.pop() || !current.starts_with(root) {
+                    return Ok(None);
+                }
+            },
+            Component::Normal(part) => {
+                let next = current.join(part);
+                match fs::symlink_metadata(&next) {
+                    Ok(ref m) if m.file_type().is_symlink() => {
+                        let target = fs::read_link(&next)?;
+                        match resolve_in(root, current, &target, depth + 1)? {
+                            Some(resolved) => current = resolved,
+                            None => return Ok(None)
+                        }
+                    },
+                    _ => current = next
+                }
+            }
+        }
+    }
+    Ok(Some(current))
+}
+
+/// Sets the permissions of a file that has been written.
+pub fn set_file_mode(path: &Path, mode: FileMode) -> io::Result<()> {
+    let file = fs::File::open(path)?;
+    let mode = match mode {
+        FileMode::Mode(mode) => mode,
+        FileMode::Executable => {
+            let current = get_mode(&file)?;
+            // Allow execution wherever reading is allowed, like `chmod +x`.
+            current | (current & 0o444) >> 2
+        }
+    };
+    set_mode(&file, mode)
+}
+
+/// The contents of an entry that is being written.
+enum PendingContents {
+    /// A file that is written to the given temporary path.
+    File(fs::File, PathBuf),
+    /// The target of a symlink.
+    Symlink(Vec<u8>)
+}
+
+/// An entry whose contents are being written. The entry only appears at its
+/// destination once it is finished; if it is dropped first, nothing is left
+/// behind.
+pub struct PendingEntry {
+    /// The destination as given.
+    dest: EntryPath,
+    /// The canonical path of the directory that the entry is written to.
+    root: PathBuf,
+    /// The destination after following symlinks.
+    real_dest: PathBuf,
+    meta: EntryMeta,
+    contents: Option<PendingContents>
+}
+
+impl PendingEntry {
+    /// Moves the entry to its destination and restores its metadata. Symlinks
+    /// are only created if they point inside of the directory of the entry.
+    pub fn finish(mut self, writer: &EntryWriter) -> errors::Result<()> {
+        match self.contents.take().unwrap() {
+            PendingContents::File(file, temp) => {
+                let res = (|| {
+                    if let Some(mode) = self.meta.mode {
+                        set_mode(&file, mode & !writer.umask)?;
+                    }
+                    if let Some(mtime) = self.meta.mtime {
+                        file.set_modified(mtime)?;
+                    }
+                    drop(file);
+                    fs::rename(&temp, &self.real_dest)
+                })();
+                if res.is_err() {
+                    let _ = fs::remove_file(&temp);
+                }
+                res?;
+            },
+            PendingContents::Symlink(target) => {
+                let target = PathBuf::from(String::from_utf8_lossy(&target).into_owned());
+                let parent = self.real_dest.parent().unwrap().to_owned();
+                if resolve_in(&self.root, parent, &target, 0)?.is_none() {
+                    return Err(errors::unsafe_link(&self.dest.joined(), &target, &self.dest.dir));
+                }
+                symlink(&target, &self.real_dest)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl io::Write for PendingEntry {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.contents.as_mut().unwrap() {
+            PendingContents::File(file, _) => file.write(buf),
+            PendingContents::Symlink(target) => {
+                target.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for PendingEntry {
+    fn drop(&mut self) {
+        if let Some(PendingContents::File(file, temp)) = self.contents.take() {
+            drop(file);
+            let _ = fs::remove_file(temp);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(not(unix))]
+fn symlink(target: &Path, path: &Path) -> io::Result<()> {
+    // Symlinks are written as files containing the target on other platforms.
+    fs::write(path, target.to_string_lossy().as_bytes())
+}
+
+#[cfg(unix)]
+fn set_mode(file: &fs::File, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    file.set_permissions(fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_file: &fs::File, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn get_mode(file: &fs::File) -> io::Result<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Ok(file.metadata()?.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn get_mode(_file: &fs::File) -> io::Result<u32> {
+    Ok(0)
+}
+
+/// Returns true if the mapping key is a pattern rather than an entry name.
+pub fn is_pattern(key: &str) -> bool {
+    key.starts_with(REGEX_PREFIX) || is_glob(key)
+}
+
+fn is_glob(s: &str) -> bool {
+    s.contains(&['*', '?', '['][..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tar::TarParser;
+    use crate::tar::tests::{archive, file, header, symlink};
+    use std::io::Write;
+
+    fn mapping(files: serde_json::Value) -> Mapping {
+        Mapping::new(&serde_json::from_value(files).unwrap()).unwrap()
+    }
+
+    fn destinations(mapping: &Mapping, name: &str) -> Vec<PathBuf> {
+        mapping.destinations(name).into_iter().map(|(path, _)| path.joined()).collect()
+    }
+
+    #[test]
+    fn mapping_exact_and_glob() {
+        let mapping = mapping(serde_json::json!({
+            "bin/tool": "tool",
+            "lib/*.so": "libs/",
+            "share/**/*.txt": "docs/",
+            "include/*.h": "header.h"
+        }));
+        assert_eq!(destinations(&mapping, "bin/tool"), [Path::new("tool")]);
+        assert!(destinations(&mapping, "bin/tool2").is_empty());
+        assert_eq!(destinations(&mapping, "lib/a.so"), [Path::new("libs/a.so")]);
+        // `*` does not match across directories, but `**` does, and the
+        // directories below the pattern are kept.
+        assert!(destinations(&mapping, "lib/sub/a.so").is_empty());
+        assert_eq!(destinations(&mapping, "share/doc/x/readme.txt"),
+                   [Path::new("docs/doc/x/readme.txt")]);
+        assert_eq!(destinations(&mapping, "include/a.h"), [Path::new("header.h")]);
+        // Directory entries are never selected by patterns.
+        assert!(destinations(&mapping, "lib/dir.so/").is_empty());
+    }
+
+    #[test]
+    fn mapping_regex() {
+        let mapping = mapping(serde_json::json!({
+            r"regex:bin/(?P<name>[a-z]+)-(\d+)": "tools/$name-v$2",
+            r"regex:lib/.*\.so": "libs/",
+            r"regex:(a|b)\.txt": "$1.out"
+        }));
+        assert_eq!(destinations(&mapping, "bin/tool-12"), [Path::new("tools/tool-v12")]);
+        // Only the part starting at the first capture comes from the archive.
+        let (dest, _) = mapping.destinations("bin/tool-12").remove(0);
+        assert_eq!((dest.dir, dest.path), (PathBuf::from("tools/"), PathBuf::from("tool-v12")));
+        assert_eq!(destinations(&mapping, "lib/sub/x.so"), [Path::new("libs/x.so")]);
+        assert_eq!(destinations(&mapping, "b.txt"), [Path::new("b.out")]);
+        // Expressions must match the whole name, including alternations.
+        assert!(destinations(&mapping, "x/bin/tool-12").is_empty());
+        assert!(destinations(&mapping, "bin/tool-12.sig").is_empty());
+        assert!(destinations(&mapping, "lib/x.so.1").is_empty());
+        assert!(destinations(&mapping, "ab.txt").is_empty());
+        assert!(destinations(&mapping, "b.txt.bak").is_empty());
+    }
+
+    #[test]
+    fn mapping_invalid_patterns() {
+        let files = |key: &str| serde_json::from_value(serde_json::json!({key: "x"})).unwrap();
+        assert!(Mapping::new(&files("regex:(")).is_err());
+        assert!(Mapping::new(&files("a/[")).is_err());
+    }
+
+    #[test]
+    fn mapping_several_keys() {
+        let mapping = mapping(serde_json::json!({"a.txt": "first", "*.txt": "second/"}));
+        // Keys are applied in sorted order.
+        assert_eq!(destinations(&mapping, "a.txt"), [Path::new("second/a.txt"), Path::new("first")]);
+    }
+
+    #[test]
+    fn stream_duplicate_destinations() {
+        let dir = TempDir::new("stream-duplicate");
+        let same = dir.0.join("same");
+        let info = serde_json::json!({"a.txt": same, r"regex:a\..*": same});
+        let mut parser = extractor(info, ExtractLimits::default());
+        let err = write_chunked(&mut parser, &archive(&[file("a.txt", b"a")])).unwrap_err();
+        assert!(err.to_string().contains("would be extracted to"), "{}", err);
+    }
+
+    #[test]
+    fn stream_captured_parent_dir() {
+        let dir = TempDir::new("stream-captured-parent");
+        let inner = dir.0.join("inner");
+        let info = serde_json::json!({"regex:x/(.*)": format!("{}/out/$1", inner.display())});
+        let mapping = mapping(info.clone());
+        assert_eq!(destinations(&mapping, "x/.."), [inner.join("out/..")]);
+        for name in &["x/..", "x/../../evil"] {
+            let mut parser = extractor(info.clone(), ExtractLimits::default());
+            let err = write_chunked(&mut parser, &archive(&[file(name, b"evil")])).unwrap_err();
+            assert!(err.to_string().contains("outside"), "{}", err);
+            drop(parser);
+        }
+        assert_eq!(dir.list(), ["inner"]);
+    }
+
+    #[test]
+    fn strip_auto() {
+        let auto = Some(StripComponents::Auto);
+        assert_eq!(strip_count(auto, vec!["top/", "top/a.txt", "./top/sub/b.txt"]), 1);
+        assert_eq!(strip_count(auto, vec!["top/a.txt", "top/sub/"]), 1);
+        // Entries under different top-level directories.
+        assert_eq!(strip_count(auto, vec!["top/a.txt", "other/b.txt"]), 0);
+        // A file at the top level, alone or next to a directory.
+        assert_eq!(strip_count(auto, vec!["a.txt"]), 0);
+        assert_eq!(strip_count(auto, vec!["top/a.txt", "b.txt"]), 0);
+        assert_eq!(strip_count(auto, vec!["top/"]), 1);
+        assert_eq!(strip_count(auto, Vec::new()), 0);
+        assert_eq!(strip_count(None, vec!["top/a.txt"]), 0);
+        assert_eq!(strip_count(Some(StripComponents::Count(2)), vec!["a.txt"]), 2);
+    }
+
+    #[test]
+    fn strip_names() {
+        assert_eq!(strip_name("top/sub/a.txt", 1), Some(PathBuf::from("sub/a.txt")));
+        assert_eq!(strip_name("./top/a.txt", 1), Some(PathBuf::from("a.txt")));
+        assert_eq!(strip_name("top/a.txt", 0), Some(PathBuf::from("top/a.txt")));
+        assert_eq!(strip_name("top/", 1), None);
+        assert_eq!(strip_name("a.txt", 2), None);
+    }
+
+    #[test]
+    fn stream_strip_auto() {
+        // The top-level directory is taken from the first entry.
+        let dir = TempDir::new("stream-strip-auto");
+        let info = serde_json::json!({"directory": dir.0, "strip_components": "auto"});
+        let mut parser = extractor(info, ExtractLimits::default());
+        let err = write_chunked(&mut parser, &archive(&[file("top/a.txt", b"a"), file("b.txt", b"b")]))
+            .unwrap_err();
+        assert!(err.to_string().contains("b.txt"), "{}", err);
+
+        // An archive that starts with a file at the top level is not stripped.
+        let dir = TempDir::new("stream-strip-root-file");
+        let info = serde_json::json!({"directory": dir.0, "strip_components": "auto"});
+        let mut parser = extractor(info, ExtractLimits::default());
+        write_chunked(&mut parser, &archive(&[file("a.txt", b"a"), file("top/b.txt", b"b")])).unwrap();
+        parser.finish().unwrap().commit().unwrap();
+        assert_eq!(dir.list(), ["a.txt", "top"]);
+    }
+
+    fn limits(limits: ExtractLimits) -> LimitTracker {
+        LimitTracker::new("test.zip", limits)
+    }
+
+    #[test]
+    fn limit_total_size() {
+        let mut tracker = limits(ExtractLimits { max_size: Some(100), ..ExtractLimits::default() });
+        tracker.start_entry("a", None, 1).unwrap();
+        tracker.add(60).unwrap();
+        tracker.start_entry("b", None, 1).unwrap();
+        tracker.add(40).unwrap();
+        let err = tracker.add(1).unwrap_err();
+        assert!(err.to_string().contains("more than 100 bytes"), "{}", err);
+
+        // Entries written to several destinations count once for each.
+        let mut tracker = limits(ExtractLimits { max_size: Some(100), ..ExtractLimits::default() });
+        tracker.start_entry("a", None, 2).unwrap();
+        tracker.add(50).unwrap();
+        assert!(tracker.add(1).is_err());
+
+        // Nested archives count towards the totals of their parent.
+        let mut tracker = limits(ExtractLimits { max_size: Some(100), ..ExtractLimits::default() });
+        let mut nested = tracker.nested("inner.zip");
+        tracker.start_entry("inner.zip", None, 1).unwrap();
+        tracker.add(80).unwrap();
+        nested.start_entry("a", None, 1).unwrap();
+        let err = nested.add(21).unwrap_err();
+        assert!(err.to_string().contains("inner.zip"), "{}", err);
+    }
+
+    #[test]
+    fn limit_entry_size() {
+        let mut tracker = limits(ExtractLimits { max_entry_size: Some(10), ..ExtractLimits::default() });
+        tracker.start_entry("a", None, 1).unwrap();
+        tracker.add(10).unwrap();
+        tracker.start_entry("b", None, 1).unwrap();
+        tracker.add(5).unwrap();
+        let err = tracker.add(6).unwrap_err();
+        assert!(err.to_string().contains("b is larger than 10 bytes"), "{}", err);
+    }
+
+    #[test]
+    fn limit_entries() {
+        let mut tracker = limits(ExtractLimits { max_entries: Some(3), ..ExtractLimits::default() });
+        tracker.start_entry("a", None, 2).unwrap();
+        tracker.start_entry("b", None, 1).unwrap();
+        let err = tracker.start_entry("c", None, 1).unwrap_err();
+        assert!(err.to_string().contains("more than 3 entries"), "{}", err);
+    }
+
+    #[test]
+    fn limit_ratio() {
+        let ratio = ExtractLimits { max_ratio: Some(10), ..ExtractLimits::default() };
+        // Small entries are not checked, however well they are compressed.
+        let mut tracker = limits(ratio);
+        tracker.start_entry("small", Some(1), 1).unwrap();
+        tracker.add(RATIO_MIN_SIZE - 1).unwrap();
+
+        let mut tracker = limits(ratio);
+        tracker.start_entry("large", Some(RATIO_MIN_SIZE / 10), 1).unwrap();
+        tracker.add(RATIO_MIN_SIZE).unwrap();
+        let err = tracker.add(RATIO_MIN_SIZE / 10).unwrap_err();
+        assert!(err.to_string().contains("large is compressed more than 10 times"), "{}", err);
+
+        // Entries without a compressed size, such as those of tar archives,
+        // are not checked.
+        let mut tracker = limits(ratio);
+        tracker.start_entry("tar", None, 1).unwrap();
+        tracker.add(RATIO_MIN_SIZE * 100).unwrap();
+    }
+
+    #[test]
+    fn limited_reader() {
+        let mut tracker = limits(ExtractLimits { max_entry_size: Some(1000), ..ExtractLimits::default() });
+        let mut contents = Vec::new();
+        LimitedReader::new(&[1; 1000][..], &mut tracker, "a", None).unwrap()
+            .read_to_end(&mut contents).unwrap();
+        assert_eq!(contents.len(), 1000);
+
+        // The bytes that are read are counted, not the size that is claimed.
+        let mut reader = LimitedReader::new(&[1; 1001][..], &mut tracker, "b", Some(1)).unwrap();
+        let err = io::copy(&mut reader, &mut io::sink()).unwrap_err();
+        assert!(err.to_string().contains("b is larger than 1000 bytes"), "{}", err);
+    }
+
+    #[test]
+    fn stream_mapping_outside_directory() {
+        // Destinations written in the template may point anywhere.
+        let dir = TempDir::new("stream-mapping-outside");
+        let info = serde_json::json!({
+            "a.txt": format!("{}/inner/../shared/a.txt", dir.0.display()),
+            "lin?": format!("{}/links/", dir.0.display())
+        });
+        let mut parser = extractor(info.clone(), ExtractLimits::default());
+        let outside = archive(&[file("a.txt", b"a"), symlink("link", "../shared/a.txt")]);
+        let err = write_chunked(&mut parser, &outside).unwrap_err();
+        // Symlinks still have to stay inside of their directory.
+        assert!(err.to_string().contains("link"), "{}", err);
+        drop(parser);
+
+        let mut parser = extractor(info, ExtractLimits::default());
+        write_chunked(&mut parser, &archive(&[file("a.txt", b"a"), symlink("link", "self")])).unwrap();
+        parser.finish().unwrap().commit().unwrap();
+        assert_eq!(dir.list(), ["inner", "links", "shared"]);
+        assert_eq!(fs::read(dir.0.join("shared/a.txt")).unwrap(), b"a");
+        assert_eq!(fs::read_link(dir.0.join("links/link")).unwrap(), Path::new("self"));
+    }
+
+    /// Creates an empty directory for a test, which is removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("tempget-test-{}-{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        /// Returns the names in the directory, sorted.
+        fn list(&self) -> Vec<String> {
+            let mut names: Vec<_> = fs::read_dir(&self.0).unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect();
+            names.sort();
+            names
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn extractor(info: serde_json::Value, limits: ExtractLimits) -> TarParser<StreamExtractor> {
+        let info: ExtractInfo = serde_json::from_value(info).unwrap();
+        let writer = EntryWriter::staged(0o022);
+        let limits = LimitTracker::new("test.tar", limits);
+        let extractor = StreamExtractor::new("test.tar", writer, &info, limits, Box::new(|_| {}));
+        TarParser::new(extractor.unwrap())
+    }
+
+    /// Writes the archive to the parser in chunks that do not line up with
+    /// the blocks of the archive.
+    fn write_chunked(parser: &mut TarParser<StreamExtractor>, archive: &[u8]) -> io::Result<()> {
+        for chunk in archive.chunks(100) {
+            parser.write_all(chunk)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn stream_directory() {
+        let dir = TempDir::new("stream-directory");
+        let info = serde_json::json!({"directory": dir.0, "strip_components": "auto"});
+        let mut parser = extractor(info, ExtractLimits::default());
+        let archive = archive(&[
+            header("top/", b'5', 0),
+            file("top/a.txt", &[b'a'; 700]),
+            file("top/sub/b.txt", b"b"),
+            symlink("top/link", "a.txt")
+        ]);
+        write_chunked(&mut parser, &archive).unwrap();
+        let mut extractor = parser.finish().unwrap();
+
+        // Nothing is moved into place until the extractor is committed.
+        assert_eq!(dir.list().len(), 1);
+        assert!(!dir.0.join("a.txt").exists());
+        extractor.commit().unwrap();
+        assert_eq!(dir.list(), ["a.txt", "link", "sub"]);
+        assert_eq!(fs::read(dir.0.join("a.txt")).unwrap(), vec![b'a'; 700]);
+        assert_eq!(fs::read(dir.0.join("sub/b.txt")).unwrap(), b"b");
+        assert_eq!(fs::read_link(dir.0.join("link")).unwrap(), Path::new("a.txt"));
+    }
+
+    #[test]
+    fn stream_mapping() {
+        let dir = TempDir::new("stream-mapping");
+        let info = serde_json::json!({
+            "top/a.txt": dir.0.join("renamed.txt"),
+            "top/*.bin": format!("{}/bin/", dir.0.display())
+        });
+        let mut parser = extractor(info, ExtractLimits::default());
+        let archive = archive(&[
+            file("top/a.txt", b"a"),
+            file("top/b.txt", b"b"),
+            file("top/c.bin", b"c")
+        ]);
+        write_chunked(&mut parser, &archive).unwrap();
+        parser.finish().unwrap().commit().unwrap();
+        assert_eq!(dir.list(), ["bin", "renamed.txt"]);
+        assert_eq!(fs::read(dir.0.join("bin/c.bin")).unwrap(), b"c");
+    }
+
+    #[test]
+    fn stream_truncated() {
+        let dir = TempDir::new("stream-truncated");
+        let info = serde_json::json!({"directory": dir.0});
+        let mut parser = extractor(info, ExtractLimits::default());
+        let archive = archive(&[file("a.txt", b"a"), file("b.txt", &[b'b'; 1000])]);
+        write_chunked(&mut parser, &archive[..1500]).unwrap();
+        assert!(parser.finish().is_err());
+        // The staging directory is removed along with the extractor.
+        assert!(dir.list().is_empty());
+    }
+
+    #[test]
+    fn stream_unsafe_path() {
+        let dir = TempDir::new("stream-unsafe");
+        let inner = dir.0.join("inner");
+        let info = serde_json::json!({"directory": inner});
+        let mut parser = extractor(info, ExtractLimits::default());
+        let archive = archive(&[file("../evil.txt", b"evil")]);
+        assert!(write_chunked(&mut parser, &archive).is_err());
+        drop(parser);
+        assert_eq!(dir.list(), ["inner"]);
+    }
+
+    #[test]
+    fn stream_limits() {
+        let dir = TempDir::new("stream-limits");
+        let info = serde_json::json!({"directory": dir.0});
+        let limits = ExtractLimits { max_entry_size: Some(100), ..ExtractLimits::default() };
+        let mut parser = extractor(info, limits);
+        let archive = archive(&[file("a.txt", &[b'a'; 100]), file("b.txt", &[b'b'; 101])]);
+        let err = write_chunked(&mut parser, &archive).unwrap_err();
+        assert!(err.to_string().contains("b.txt is larger than 100 bytes"), "{}", err);
+    }
+}
@@ -1,15 +1,235 @@
-use reqwest::Method;
+use reqwest::{Method, StatusCode, Url};
+use reqwest::header::{HeaderMap, CONTENT_DISPOSITION, CONTENT_RANGE, RANGE};
 use reqwest::r#async::Request;
+use url::percent_encoding::percent_decode;
+use crate::errors;
 use crate::template::Template;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Seek, SeekFrom};
 
-/// Generates a mapping of file to HTTP requests
-pub fn get_template_requests(templ: &Template) -> HashMap<String, Request> {
+/// The size of the smallest range requested by a `RangeReader`.
+const MIN_RANGE_SIZE: u64 = 64 * 1024;
+/// The size of the largest range requested by a `RangeReader`.
+const MAX_RANGE_SIZE: u64 = 8 * 1024 * 1024;
+/// The number of downloaded ranges that a `RangeReader` keeps in memory.
+const CACHED_RANGES: usize = 4;
+
+/// Generates a mapping of file to HTTP requests. Each file has a request for
+/// its URL, followed by a request for each of its mirrors.
+pub fn get_template_requests(templ: &Template) -> HashMap<String, Vec<Request>> {
     let mut data = HashMap::new();
-    for (file_name, url) in &templ.retrieve {
-        let url = url.clone().into_inner();
-        let req = Request::new(Method::GET, url.clone());
-        data.insert(file_name.clone(), req);
+    for (file_name, info) in &templ.retrieve {
+        let reqs = std::iter::once(info.url())
+            .chain(info.mirrors())
+            .map(|url| Request::new(Method::GET, url.clone()))
+            .collect();
+        data.insert(file_name.clone(), reqs);
     }
     data
 }
+
+/// Sends a HEAD request to the URL and returns the size of the file, or `None`
+/// if the request fails or the server does not report the size.
+pub fn head_size(client: &reqwest::Client, url: &Url) -> Option<u64> {
+    let response = client.head(url.clone()).send().ok()?;
+    if response.status().is_success() {
+        response.content_length()
+    } else {
+        None
+    }
+}
+
+/// Returns the file name given by the last segment of the URL's path, if it is
+/// not empty.
+pub fn file_name_from_url(url: &Url) -> Option<String> {
+    let segment = url.path_segments()?.next_back()?;
+    let name = percent_decode(segment.as_bytes()).decode_utf8_lossy();
+    sanitize_file_name(&name)
+}
+
+/// Returns the file name given by the value of a `Content-Disposition` header,
+/// if any. The extended `filename*` parameter is preferred over `filename`.
+pub fn file_name_from_disposition(value: &str) -> Option<String> {
+    let mut plain = None;
+    let mut extended = None;
+    for param in value.split(';').skip(1) {
+        let mut parts = param.splitn(2, '=');
+        let key = parts.next()?.trim().to_lowercase();
+        let value = parts.next().unwrap_or("").trim();
+        if key == "filename" {
+            plain = Some(value.trim_matches('"').replace("\\\"", "\""));
+        } else if key == "filename*" {
+            // Given as `charset'language'percent-encoded-name`.
+            let encoded = value.splitn(3, '\'').nth(2)?;
+            extended = Some(percent_decode(encoded.as_bytes()).decode_utf8_lossy().into_owned());
+        }
+    }
+    extended.or(plain).and_then(|name| sanitize_file_name(&name))
+}
+
+/// Returns the file name suggested by a response, using its
+/// `Content-Disposition` header if it has one, or the last segment of the
+/// given URL otherwise.
+pub fn response_file_name(headers: &HeaderMap, url: &Url) -> Option<String> {
+    headers.get(CONTENT_DISPOSITION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(file_name_from_disposition)
+        .or_else(|| file_name_from_url(url))
+}
+
+/// Determines the name of the file at the URL, for a `retrieve` entry that
+/// refers to a directory. The name is taken from the `Content-Disposition`
+/// header of the response, or from the final URL after any redirects. A
+/// `HEAD` request is sent first, and a `GET` request (whose body is not read)
+/// if the server does not support `HEAD`.
+pub fn resolve_file_name(client: &reqwest::Client, url: &Url) -> errors::Result<String> {
+    let mut response = client.head(url.clone()).send()?;
+    if !response.status().is_success() {
+        response = client.get(url.clone()).send()?;
+    }
+    if !response.status().is_success() {
+        return Err(errors::status_code(response.status()));
+    }
+    response_file_name(response.headers(), response.url())
+        .or_else(|| file_name_from_url(url))
+        .ok_or_else(|| errors::no_file_name(url))
+}
+
+/// Strips any directories from a file name given by a server, so that it
+/// cannot be used to write outside of the intended directory.
+fn sanitize_file_name(name: &str) -> Option<String> {
+    let name = name.rsplit(&['/', '\\'][..]).next()?.trim();
+    if name.is_empty() || name == "." || name == ".." {
+        None
+    } else {
+        Some(name.to_owned())
+    }
+}
+
+/// A reader for a remote file, which downloads only the parts of the file that
+/// are read, using HTTP range requests. This allows reading a few files from a
+/// large zip archive. Parts are downloaded in blocks of at least
+/// `MIN_RANGE_SIZE` bytes, which grow while the file is read sequentially.
+pub struct RangeReader {
+    client: reqwest::Client,
+    url: Url,
+    len: u64,
+    pos: u64,
+    /// The most recently downloaded ranges, with their offsets.
+    cache: VecDeque<(u64, Vec<u8>)>,
+    /// The size of the last range that was downloaded.
+    range_size: u64,
+    /// The total number of bytes downloaded.
+    downloaded: u64
+}
+
+impl RangeReader {
+    /// Opens the file at the URL. Returns an error if the server does not
+    /// support range requests.
+    pub fn new(client: reqwest::Client, url: &Url) -> errors::Result<Self> {
+        let mut reader = RangeReader {
+            client,
+            url: url.clone(),
+            len: 0,
+            pos: 0,
+            cache: VecDeque::new(),
+            range_size: 0,
+            downloaded: 0
+        };
+        // The first request finds the length of the file.
+        let (data, len) = reader.fetch(0, 1)?;
+        reader.len = len;
+        reader.downloaded = data.len() as u64;
+        Ok(reader)
+    }
+
+    /// Returns the size of the file in bytes.
+    pub fn size(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns the number of bytes that have been downloaded.
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded
+    }
+
+    /// Downloads the bytes from `start` to `end` (exclusive), returning them
+    /// along with the length of the file.
+    fn fetch(&self, start: u64, end: u64) -> errors::Result<(Vec<u8>, u64)> {
+        let mut response = self.client.get(self.url.clone())
+            .header(RANGE, format!("bytes={}-{}", start, end - 1))
+            .send()?;
+        if response.status().is_success() && response.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(errors::ranges_unsupported(&self.url));
+        } else if !response.status().is_success() {
+            return Err(errors::status_code(response.status()));
+        }
+        // The header has the form `bytes <start>-<end>/<length>`.
+        let len: u64 = response.headers().get(CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.rsplit('/').next())
+            .and_then(|len| len.parse().ok())
+            .ok_or_else(|| errors::ranges_unsupported(&self.url))?;
+        let mut data = Vec::with_capacity((end - start) as usize);
+        response.read_to_end(&mut data)?;
+        if data.len() as u64 != end.min(len).saturating_sub(start) {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      "the server returned the wrong number of bytes").into());
+        }
+        Ok((data, len))
+    }
+}
+
+impl Read for RangeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.pos >= self.len {
+            return Ok(0);
+        }
+        let pos = self.pos;
+        let cached = self.cache.iter()
+            .position(|(start, data)| *start <= pos && pos < start + data.len() as u64);
+        let index = match cached {
+            Some(index) => index,
+            None => {
+                // Download larger ranges while the file is read sequentially,
+                // and aligned blocks otherwise.
+                let is_sequential = self.cache.back()
+                    .is_some_and(|(start, data)| start + data.len() as u64 == pos);
+                let (start, size) = if is_sequential {
+                    (pos, (self.range_size * 2).min(MAX_RANGE_SIZE))
+                } else {
+                    (pos - pos % MIN_RANGE_SIZE, MIN_RANGE_SIZE)
+                };
+                let end = (start + size).min(self.len);
+                let (data, _) = self.fetch(start, end)
+                    .map_err(|err| io::Error::other(err.to_string()))?;
+                self.range_size = size;
+                self.downloaded += data.len() as u64;
+                self.cache.push_back((start, data));
+                if self.cache.len() > CACHED_RANGES {
+                    self.cache.pop_front();
+                }
+                self.cache.len() - 1
+            }
+        };
+        let (start, data) = &self.cache[index];
+        let offset = (pos - start) as usize;
+        let n = buf.len().min(data.len() - offset);
+        buf[..n].copy_from_slice(&data[offset..offset + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for RangeReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset)
+        };
+        self.pos = pos.ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidInput, "invalid seek to a negative position"))?;
+        Ok(self.pos)
+    }
+}
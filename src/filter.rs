@@ -0,0 +1,46 @@
+use glob::{MatchOptions, Pattern};
+use std::path::Path;
+
+use crate::template::Template;
+
+#[derive(Debug, Clone, Default)]
+/// Selects a subset of the files in a template, based on their paths and the
+/// groups that they belong to.
+pub struct Filter {
+    /// When not empty, only paths matching at least one of these patterns are
+    /// selected.
+    pub only: Vec<Pattern>,
+    /// Paths matching any of these patterns are not selected.
+    pub exclude: Vec<Pattern>,
+    /// When not empty, only files belonging to at least one of these groups
+    /// are selected.
+    pub groups: Vec<String>
+}
+
+impl Filter {
+    /// Returns true if the file at the given path, which belongs to the given
+    /// groups, is selected by this filter.
+    pub fn selects(&self, path: &str, groups: &[String]) -> bool {
+        let opts = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        };
+        let path = Path::new(path);
+        let matches = |p: &Pattern| p.matches_path_with(path, opts);
+        (self.only.is_empty() || self.only.iter().any(matches))
+            && !self.exclude.iter().any(matches)
+            && (self.groups.is_empty() || groups.iter().any(|g| self.groups.contains(g)))
+    }
+
+    /// Removes the files that are not selected by this filter from the
+    /// template. An archive is only extracted if it is selected; if the
+    /// archive is retrieved, it belongs to the groups of its `retrieve` entry.
+    pub fn apply(&self, templ: &mut Template) {
+        let retrieve = &templ.retrieve;
+        templ.extract.retain(|archive, _| {
+            let groups = retrieve.get(archive).map(|info| info.groups()).unwrap_or(&[]);
+            self.selects(archive, groups)
+        });
+        templ.retrieve.retain(|path, info| self.selects(path, info.groups()));
+    }
+}
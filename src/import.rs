@@ -0,0 +1,319 @@
+use std::path::{Component, Path};
+use reqwest::Url;
+
+use crate::errors;
+use crate::fetcher;
+use crate::template::{RetrieveInfo, RetrieveOptions, Template};
+
+/// The XML namespace of Metalink 4 documents (RFC 5854).
+const METALINK_NS: &str = "urn:ietf:params:xml:ns:metalink";
+
+#[derive(Debug, Clone)]
+/// A file described by a Metalink document.
+pub struct MetalinkFile {
+    /// The path of the file, relative to the download directory.
+    pub name: String,
+    /// The HTTP and HTTPS URLs of the file, from most to least preferred.
+    pub urls: Vec<Url>,
+    /// The size of the file in bytes.
+    pub size: Option<u64>,
+    /// The SHA-256 checksum of the file.
+    pub sha256: Option<String>
+}
+
+impl MetalinkFile {
+    /// Returns the options for downloading the file from its most preferred
+    /// URL, using the other URLs as mirrors.
+    pub fn retrieve_options(&self) -> errors::Result<RetrieveOptions> {
+        let mut urls = self.urls.iter().cloned();
+        let url = urls.next().ok_or_else(|| errors::invalid_metalink(
+            &format!("file {:?} has no HTTP or HTTPS URLs", self.name)))?;
+        let mut opts = RetrieveOptions::new(url);
+        opts.mirrors = urls.map(url_serde::Serde).collect();
+        opts.size = self.size;
+        opts.sha256 = self.sha256.clone();
+        Ok(opts)
+    }
+}
+
+/// Parses a Metalink 4 document. URLs with schemes other than HTTP and HTTPS
+/// are ignored, as are hashes other than SHA-256.
+pub fn parse_metalink(contents: &str) -> errors::Result<Vec<MetalinkFile>> {
+    let doc = roxmltree::Document::parse(contents)
+        .map_err(|err| errors::invalid_metalink(&err.to_string()))?;
+    let root = doc.root_element();
+    if !root.has_tag_name((METALINK_NS, "metalink")) {
+        return Err(errors::invalid_metalink("the root element is not `metalink`"));
+    }
+
+    let mut files = Vec::new();
+    for file in root.children().filter(|n| n.has_tag_name((METALINK_NS, "file"))) {
+        let name = file.attribute("name")
+            .ok_or_else(|| errors::invalid_metalink("a file has no name"))?;
+        if !is_safe_path(name) {
+            return Err(errors::invalid_metalink(&format!("invalid file name {:?}", name)));
+        }
+
+        let mut urls = Vec::new();
+        let mut size = None;
+        let mut sha256 = None;
+        for child in file.children().filter(|n| n.is_element()) {
+            let text = child.text().unwrap_or("").trim();
+            match child.tag_name().name() {
+                "url" => {
+                    let url = match Url::parse(text) {
+                        Ok(url) => url,
+                        Err(_) => continue
+                    };
+                    if url.scheme() == "http" || url.scheme() == "https" {
+                        // URLs without a priority are the least preferred.
+                        let priority = child.attribute("priority")
+                            .and_then(|p| p.parse::<u32>().ok())
+                            .unwrap_or(u32::MAX);
+                        urls.push((priority, url));
+                    }
+                },
+                "size" => {
+                    size = Some(text.parse().map_err(|_| errors::invalid_metalink(
+                        &format!("invalid size {:?} for file {:?}", text, name)))?);
+                },
+                "hash" if child.attribute("type") == Some("sha-256") => {
+                    sha256 = Some(text.to_lowercase());
+                },
+                _ => {}
+            }
+        }
+        urls.sort_by_key(|(priority, _)| *priority);
+        files.push(MetalinkFile {
+            name: name.to_owned(),
+            urls: urls.into_iter().map(|(_, url)| url).collect(),
+            size,
+            sha256
+        });
+    }
+    Ok(files)
+}
+
+/// Returns true if the path only consists of file and directory names, and
+/// is not empty. Paths in imported files may contain directories, but must
+/// not escape the download directory.
+fn is_safe_path(path: &str) -> bool {
+    !path.is_empty() && Path::new(path).components().all(|c| matches!(c, Component::Normal(_)))
+}
+
+/// Creates a template that downloads each of the files in a Metalink
+/// document.
+pub fn from_metalink(contents: &str) -> errors::Result<Template> {
+    let mut builder = Template::builder();
+    for file in parse_metalink(contents)? {
+        let opts = file.retrieve_options()?;
+        builder = builder.retrieve_info(file.name, RetrieveInfo::Detailed(opts));
+    }
+    Ok(builder.build())
+}
+
+/// Creates a template from an aria2 input file. Each line that does not start
+/// with whitespace lists the tab-separated URLs of a file, and is followed by
+/// indented `name=value` options for the file. The `dir`, `out`, and
+/// `checksum` options are used, and other options are ignored.
+pub fn from_aria2(contents: &str) -> errors::Result<Template> {
+    struct Entry {
+        /// The line number of the URLs.
+        line: usize,
+        urls: Vec<Url>,
+        dir: Option<String>,
+        out: Option<String>,
+        sha256: Option<String>
+    }
+
+    let mut entries = Vec::<Entry>::new();
+    for (idx, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+        if line.starts_with(&[' ', '\t'][..]) {
+            let entry = match entries.last_mut() {
+                Some(entry) => entry,
+                None => continue
+            };
+            let mut parts = line.trim().splitn(2, '=');
+            let name = parts.next().unwrap_or("");
+            let value = parts.next().unwrap_or("").to_owned();
+            match name {
+                "dir" => entry.dir = Some(value),
+                "out" => entry.out = Some(value),
+                "checksum" => {
+                    let mut parts = value.splitn(2, '=');
+                    if parts.next().unwrap_or("").eq_ignore_ascii_case("sha-256") {
+                        entry.sha256 = parts.next().map(str::to_lowercase);
+                    }
+                },
+                _ => {}
+            }
+            continue;
+        }
+
+        let urls = line.split('\t')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(|url| Url::parse(url)
+                 .map_err(|err| errors::invalid_url(idx + 1, url, &err.to_string())))
+            .collect::<errors::Result<Vec<_>>>()?;
+        entries.push(Entry { line: idx + 1, urls, dir: None, out: None, sha256: None });
+    }
+
+    let mut names = std::collections::HashSet::new();
+    let mut builder = Template::builder();
+    for entry in entries {
+        let mut urls = entry.urls.into_iter();
+        let url = match urls.next() {
+            Some(url) => url,
+            None => continue
+        };
+        let out = entry.out
+            .or_else(|| fetcher::file_name_from_url(&url))
+            .unwrap_or_else(|| "index.html".to_owned());
+        let path = match entry.dir {
+            Some(dir) => format!("{}/{}", dir.trim_end_matches('/'), out),
+            None => out
+        };
+        if !is_safe_path(&path) {
+            return Err(errors::invalid_aria2(
+                &format!("invalid file name {:?} for the URLs on line {}", path, entry.line)));
+        }
+        let mut opts = RetrieveOptions::new(url);
+        opts.mirrors = urls.map(url_serde::Serde).collect();
+        opts.sha256 = entry.sha256;
+        let path = crate::init::unique_name(&mut names, path);
+        builder = builder.retrieve_info(path, RetrieveInfo::Detailed(opts));
+    }
+    Ok(builder.build())
+}
+
+/// Returns true if the URL refers to a Metalink document, judging by its
+/// `.meta4` extension.
+pub fn is_metalink_url(url: &Url) -> bool {
+    url.path().ends_with(".meta4")
+}
+
+/// Downloads the Metalink document that the entry refers to, and returns an
+/// entry that downloads the file that it describes instead, along with the
+/// name of the file in the document. If the document describes several files,
+/// the file with the same name as the destination path is used. The checksum
+/// and other options of the entry take precedence over the document.
+pub fn resolve_metalink(client: &reqwest::Client, path: &str, info: &RetrieveInfo)
+                        -> errors::Result<(String, RetrieveInfo)> {
+    let mut response = client.get(info.url().clone()).send()?;
+    if !response.status().is_success() {
+        return Err(errors::status_code(response.status()));
+    }
+    let files = parse_metalink(&response.text()?)?;
+    let file_name = Path::new(path).file_name().and_then(|n| n.to_str());
+    let file = match files.len() {
+        1 => &files[0],
+        _ => files.iter()
+            .find(|f| Some(f.name.as_str()) == file_name || f.name == path)
+            .ok_or_else(|| errors::invalid_metalink(
+                &format!("the document does not describe a file named {:?}", path)))?
+    };
+
+    let found = file.retrieve_options()?;
+    let opts = match info {
+        // Only the URLs come from the document, along with the size and
+        // checksum if the entry does not give them.
+        RetrieveInfo::Detailed(orig) => RetrieveOptions {
+            url: found.url,
+            mirrors: found.mirrors,
+            size: orig.size.or(found.size),
+            sha256: orig.sha256.clone().or(found.sha256),
+            ..orig.clone()
+        },
+        _ => found
+    };
+    Ok((file.name.clone(), RetrieveInfo::Detailed(opts)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metalink(name: &str) -> String {
+        format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+            <metalink xmlns="urn:ietf:params:xml:ns:metalink">
+              <file name="{}">
+                <size>12</size>
+                <hash type="sha-1">ignored</hash>
+                <hash type="sha-256">ABCDEF</hash>
+                <url>ftp://example.com/ignored</url>
+                <url>https://example.com/fallback</url>
+                <url priority="1">https://example.com/first</url>
+              </file>
+            </metalink>"#, name)
+    }
+
+    #[test]
+    fn metalink_files() {
+        let files = parse_metalink(&metalink("dir/file.txt")).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].name, "dir/file.txt");
+        assert_eq!(files[0].size, Some(12));
+        assert_eq!(files[0].sha256.as_deref(), Some("abcdef"));
+        let urls: Vec<_> = files[0].urls.iter().map(Url::as_str).collect();
+        assert_eq!(urls, ["https://example.com/first", "https://example.com/fallback"]);
+    }
+
+    #[test]
+    fn metalink_unsafe_names() {
+        for name in &["/etc/passwd", "../file.txt", "dir/../../file.txt", "./file.txt", ""] {
+            let err = parse_metalink(&metalink(name)).unwrap_err();
+            assert!(err.to_string().contains("invalid file name"), "{:?}: {}", name, err);
+        }
+    }
+
+    #[test]
+    fn metalink_invalid_documents() {
+        assert!(parse_metalink("<metalink").is_err());
+        assert!(parse_metalink("<other xmlns=\"urn:ietf:params:xml:ns:metalink\"/>").is_err());
+        assert!(parse_metalink(&metalink("file").replace("<size>12", "<size>x")).is_err());
+    }
+
+    #[test]
+    fn aria2_paths() {
+        let templ = from_aria2("\
+# A comment
+https://example.com/a.txt\thttps://mirror.example.com/a.txt
+  dir=downloads/
+  out=renamed.txt
+  checksum=sha-256=ABC
+  split=4
+https://example.com/b.txt
+  dir=downloads
+https://example.com/dir/c.txt
+  out=sub/c.txt
+https://example.com/d.txt
+").unwrap();
+        let mut paths: Vec<_> = templ.retrieve.keys().map(String::as_str).collect();
+        paths.sort();
+        assert_eq!(paths, ["d.txt", "downloads/b.txt", "downloads/renamed.txt", "sub/c.txt"]);
+        let info = &templ.retrieve["downloads/renamed.txt"];
+        assert_eq!(info.url().as_str(), "https://example.com/a.txt");
+        assert_eq!(info.mirrors()[0].as_str(), "https://mirror.example.com/a.txt");
+        assert_eq!(info.sha256(), Some("abc"));
+    }
+
+    #[test]
+    fn aria2_unsafe_paths() {
+        let inputs = [
+            "https://example.com/a\n  dir=/tmp\n",
+            "https://example.com/a\n  dir=..\n",
+            "https://example.com/a\n  out=../a\n",
+            "https://example.com/a\n  dir=downloads\n  out=../../a\n",
+            "https://example.com/a\n  out=/etc/passwd\n",
+            "https://example.com/a\n  out=\n"
+        ];
+        for input in &inputs {
+            let err = from_aria2(input).unwrap_err();
+            assert!(err.to_string().contains("line 1"), "{:?}: {}", input, err);
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::time::Duration;
+use reqwest::Url;
+
+use crate::errors;
+use crate::fetcher;
+use crate::template::{RetrieveInfo, RetrieveOptions, Template, TemplateSource};
+
+#[derive(Debug, Clone)]
+/// Options that control how a template is generated from a list of URLs.
+pub struct InitOptions {
+    /// When true, each file is downloaded once to compute its checksum.
+    pub checksums: bool,
+    /// The maximum amount of time to wait for each request.
+    pub timeout: Duration
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The format of the input to `tempget init`.
+pub enum InputFormat {
+    /// A list of URLs, with one URL per line.
+    List,
+    /// An aria2 input file.
+    Aria2,
+    /// A Metalink 4 document.
+    Metalink
+}
+
+impl InputFormat {
+    /// Determines the format of the input from its file extension. Inputs
+    /// ending in `.meta4` are Metalink documents, and anything else is a list
+    /// of URLs.
+    pub fn detect(source: &TemplateSource) -> Self {
+        if source.to_string().ends_with(".meta4") {
+            InputFormat::Metalink
+        } else {
+            InputFormat::List
+        }
+    }
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "list" => Ok(InputFormat::List),
+            "aria2" => Ok(InputFormat::Aria2),
+            "metalink" => Ok(InputFormat::Metalink),
+            _ => Err(format!("unknown input format: {}", s))
+        }
+    }
+}
+
+/// Parses a list of URLs with one URL per line, as accepted by `wget -i`.
+/// Blank lines and lines starting with `#` are ignored.
+pub fn parse_url_list(contents: &str) -> errors::Result<Vec<Url>> {
+    let mut urls = Vec::new();
+    for (idx, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let url = Url::parse(line)
+            .map_err(|err| errors::invalid_url(idx + 1, line, &err.to_string()))?;
+        urls.push(url);
+    }
+    Ok(urls)
+}
+
+/// Creates a template that downloads each of the URLs into the current
+/// directory. File names are taken from the `Content-Disposition` header of
+/// the response if the server sends one, or from the URL otherwise. Like
+/// `wget`, a suffix such as `.1` is added when two URLs have the same name.
+pub fn template_from_urls(urls: &[Url], options: &InitOptions) -> errors::Result<Template> {
+    let client = reqwest::Client::builder()
+        .timeout(options.timeout)
+        .build()?;
+    let mut names = HashSet::new();
+    let mut builder = Template::builder();
+    for url in urls {
+        let (name, sha256) = if options.checksums {
+            eprintln!("Downloading {}", url);
+            let mut response = client.get(url.clone()).send()?;
+            if !response.status().is_success() {
+                return Err(errors::status_code(response.status()));
+            }
+            let name = fetcher::response_file_name(response.headers(), url)
+                .or_else(|| fetcher::file_name_from_url(response.url()));
+            let sha256 = crate::checksum::sha256_reader(&mut response)?;
+            (name, Some(sha256))
+        } else {
+            // The name can usually be determined without contacting the
+            // server, but the `Content-Disposition` header takes precedence.
+            let name = client.head(url.clone()).send().ok()
+                .filter(|response| response.status().is_success())
+                .and_then(|response| {
+                    fetcher::response_file_name(response.headers(), url)
+                        .or_else(|| fetcher::file_name_from_url(response.url()))
+                })
+                .or_else(|| fetcher::file_name_from_url(url));
+            (name, None)
+        };
+
+        let name = unique_name(&mut names, name.unwrap_or_else(|| "index.html".to_owned()));
+        builder = match sha256 {
+            Some(sha256) => {
+                let mut opts = RetrieveOptions::new(url.clone());
+                opts.sha256 = Some(sha256);
+                builder.retrieve_info(name, RetrieveInfo::Detailed(opts))
+            },
+            None => builder.retrieve(name, url.clone())
+        };
+    }
+    Ok(builder.build())
+}
+
+/// Returns the name with a numeric suffix added if it has already been used,
+/// and records the returned name as used.
+pub(crate) fn unique_name(names: &mut HashSet<String>, name: String) -> String {
+    let mut unique = name.clone();
+    let mut n = 1;
+    while names.contains(&unique) {
+        unique = format!("{}.{}", name, n);
+        n += 1;
+    }
+    names.insert(unique.clone());
+    unique
+}
@@ -4,6 +4,17 @@ extern crate failure;
 pub mod template;
 pub mod fetcher;
 pub mod cli;
+pub mod check;
+pub mod filter;
+pub mod vars;
+pub mod platform;
+pub mod checksum;
+pub mod init;
+pub mod import;
+pub mod export;
+pub mod extract;
+pub mod decompress;
+pub mod tar;
 
 pub mod errors {
     pub type Result<T> = ::std::result::Result<T, failure::Error>;
@@ -21,10 +32,20 @@ pub mod errors {
         pub code: ::reqwest::StatusCode
     }
 
+    #[derive(Fail, Debug)]
+    #[fail(display = "the server for {} does not support range requests", url)]
+    /// An archive that is extracted partially is hosted on a server that does
+    /// not support HTTP range requests.
+    pub struct RangesUnsupported {
+        pub url: ::reqwest::Url
+    }
+
     #[derive(Fail, Debug)]
     /// Files which have failed to download
     pub struct DownloadsFailed {
-        pub files: Vec<(std::path::PathBuf, String)>
+        pub files: Vec<(std::path::PathBuf, String)>,
+        /// The number of files that were attempted to be downloaded.
+        pub total: usize
     }
 
     impl std::fmt::Display for DownloadsFailed {
@@ -36,6 +57,178 @@ pub mod errors {
         }
     }
 
+    #[derive(Fail, Debug)]
+    #[fail(display = "found {} error(s) in the template", _0)]
+    /// Checking the template found errors. Annotated with the number of errors.
+    pub struct CheckFailed(usize);
+
+    #[derive(Fail, Debug)]
+    #[fail(display = "undefined variable `{}` in {}", name, context)]
+    /// A template refers to a variable that is not defined.
+    pub struct UndefinedVariable {
+        pub name: String,
+        /// Describes where the variable is used.
+        pub context: String
+    }
+
+    #[derive(Fail, Debug)]
+    #[fail(display = "variable `{}` refers to itself", _0)]
+    /// A template variable refers to itself, possibly through other variables.
+    pub struct RecursiveVariable(String);
+
+    #[derive(Fail, Debug)]
+    #[fail(display = "duplicate key {:?} after substituting variables", _0)]
+    /// Two keys in a template section are the same once variables are
+    /// substituted.
+    pub struct DuplicateKey(String);
+
+    #[derive(Fail, Debug)]
+    #[fail(display = "{} {:?}: {}", section, key, reason)]
+    /// An entry in the `retrieve` or `extract` section has an invalid
+    /// combination of options.
+    pub struct InvalidEntry {
+        pub section: String,
+        pub key: String,
+        pub reason: String
+    }
+
+    #[derive(Fail, Debug)]
+    #[fail(display = "{} entry {:?} is defined in both {} and {}", section, key, first, second)]
+    /// Two templates that are included together define the same entry.
+    pub struct ConflictingEntry {
+        pub section: String,
+        pub key: String,
+        pub first: String,
+        pub second: String
+    }
+
+    #[derive(Fail, Debug)]
+    #[fail(display = "template {} includes itself", _0)]
+    /// A template includes itself, possibly through other templates.
+    pub struct RecursiveInclude(String);
+
+    #[derive(Fail, Debug)]
+    #[fail(display = "{} cannot include the remote template {}, since it would not be verified \
+                      by --template-sha256", template, include)]
+    /// A template that is verified with a checksum includes a remote template,
+    /// whose contents are not covered by the checksum.
+    pub struct UnverifiedInclude {
+        pub template: String,
+        pub include: String
+    }
+
+    #[derive(Fail, Debug)]
+    #[fail(display = "checksum mismatch for {}: expected {}, found {}", name, expected, actual)]
+    /// The contents of a file do not match the expected checksum.
+    pub struct ChecksumMismatch {
+        pub name: String,
+        pub expected: String,
+        pub actual: String
+    }
+
+    #[derive(Fail, Debug)]
+    #[fail(display = "size mismatch for {}: expected {} bytes, found {} bytes", name, expected, actual)]
+    /// A file does not have the expected size.
+    pub struct SizeMismatch {
+        pub name: String,
+        pub expected: u64,
+        pub actual: u64
+    }
+
+    #[derive(Fail, Debug)]
+    #[fail(display = "invalid URL {:?} on line {}: {}", url, line, reason)]
+    /// A line in a list of URLs is not a valid URL.
+    pub struct InvalidUrl {
+        pub line: usize,
+        pub url: String,
+        pub reason: String
+    }
+
+    #[derive(Fail, Debug)]
+    #[fail(display = "invalid Metalink document: {}", _0)]
+    /// A Metalink document is malformed or cannot be used.
+    pub struct InvalidMetalink(String);
+
+    #[derive(Fail, Debug)]
+    #[fail(display = "invalid aria2 input file: {}", _0)]
+    /// An aria2 input file is malformed or cannot be used.
+    pub struct InvalidAria2(String);
+
+    #[derive(Fail, Debug)]
+    #[fail(display = "could not determine a file name for {}", _0)]
+    /// Neither the response nor the URL of a file gives a file name.
+    pub struct NoFileName(String);
+
+    #[derive(Fail, Debug)]
+    #[fail(display = "retrieve entries {:?} and {:?} both resolve to {:?}", first, second, path)]
+    /// Two entries in the `retrieve` section resolve to the same file.
+    pub struct ResolvedConflict {
+        pub path: String,
+        pub first: String,
+        pub second: String
+    }
+
+    #[derive(Fail, Debug)]
+    #[fail(display = "invalid pattern {:?}: {}", key, reason)]
+    /// A key in an extract mapping is not a valid glob pattern or regular
+    /// expression.
+    pub struct InvalidPattern {
+        pub key: String,
+        pub reason: String
+    }
+
+    #[derive(Fail, Debug)]
+    #[fail(display = "multiple entries in {} would be extracted to {}", archive, path)]
+    /// Several entries in an archive are extracted to the same location.
+    pub struct DuplicateDestination {
+        pub archive: String,
+        pub path: String
+    }
+
+    #[derive(Fail, Debug)]
+    #[fail(display = "{}", _0)]
+    /// A template does not have the expected structure.
+    pub struct InvalidTemplate(String);
+
+    #[derive(Fail, Debug)]
+    #[fail(display = "{} is not inside of {}, so the top-level directory cannot be removed",
+           name, top)]
+    /// While extracting an archive as it is downloaded with
+    /// `strip_components = "auto"`, an entry is not inside of the top-level
+    /// directory of the first entry.
+    pub struct NotInTopDir {
+        pub name: String,
+        pub top: String
+    }
+
+    #[derive(Fail, Debug)]
+    #[fail(display = "{} exceeds the extraction limits: {}", archive, reason)]
+    /// Extracting an archive would write more than the configured limits
+    /// allow.
+    pub struct LimitExceeded {
+        pub archive: String,
+        pub reason: String
+    }
+
+    #[derive(Fail, Debug)]
+    #[fail(display = "{} is outside of {}", path, dir)]
+    /// An entry in an archive would be written outside of the directory that
+    /// it is extracted to, possibly by following a symlink.
+    pub struct UnsafePath {
+        pub path: String,
+        pub dir: String
+    }
+
+    #[derive(Fail, Debug)]
+    #[fail(display = "the symlink {} points to {}, which is outside of {}", path, target, dir)]
+    /// A symlink in an archive points outside of the directory that it is
+    /// extracted to.
+    pub struct UnsafeLink {
+        pub path: String,
+        pub target: String,
+        pub dir: String
+    }
+
     /// Constructs a `Timeout` error
     pub fn timeout(seconds: u64) -> Error {
         Timeout(seconds).into()
@@ -46,8 +239,235 @@ pub mod errors {
         (StatusCode { code }).into()
     }
 
-    /// Constructs a `DownloadsFailed` error
-    pub fn download_failed(files: Vec<(std::path::PathBuf, String)>) -> Error {
-        DownloadsFailed { files }.into()
+    /// Constructs a `RangesUnsupported` error
+    pub fn ranges_unsupported(url: &::reqwest::Url) -> Error {
+        RangesUnsupported { url: url.clone() }.into()
+    }
+
+    /// Constructs a `CheckFailed` error
+    pub fn check_failed(errors: usize) -> Error {
+        CheckFailed(errors).into()
+    }
+
+    /// Constructs an `UndefinedVariable` error
+    pub fn undefined_variable(name: &str, context: &str) -> Error {
+        UndefinedVariable { name: name.to_owned(), context: context.to_owned() }.into()
+    }
+
+    /// Constructs a `RecursiveVariable` error
+    pub fn recursive_variable(name: &str) -> Error {
+        RecursiveVariable(name.to_owned()).into()
+    }
+
+    /// Constructs a `DuplicateKey` error
+    pub fn duplicate_key(key: &str) -> Error {
+        DuplicateKey(key.to_owned()).into()
+    }
+
+    /// Constructs an `InvalidEntry` error
+    pub fn invalid_entry(section: &str, key: &str, reason: &str) -> Error {
+        InvalidEntry {
+            section: section.to_owned(),
+            key: key.to_owned(),
+            reason: reason.to_owned()
+        }.into()
+    }
+
+    /// Constructs a `ConflictingEntry` error
+    pub fn conflicting_entry(section: &str, key: &str, first: &str, second: &str) -> Error {
+        ConflictingEntry {
+            section: section.to_owned(),
+            key: key.to_owned(),
+            first: first.to_owned(),
+            second: second.to_owned()
+        }.into()
+    }
+
+    /// Constructs an `UnverifiedInclude` error
+    pub fn unverified_include(template: &str, include: &str) -> Error {
+        UnverifiedInclude { template: template.to_owned(), include: include.to_owned() }.into()
+    }
+
+    /// Constructs a `RecursiveInclude` error
+    pub fn recursive_include(template: &str) -> Error {
+        RecursiveInclude(template.to_owned()).into()
+    }
+
+    /// Constructs a `ChecksumMismatch` error
+    pub fn checksum_mismatch(name: &str, expected: &str, actual: &str) -> Error {
+        ChecksumMismatch {
+            name: name.to_owned(),
+            expected: expected.to_owned(),
+            actual: actual.to_owned()
+        }.into()
+    }
+
+    /// Constructs a `SizeMismatch` error
+    pub fn size_mismatch(name: &str, expected: u64, actual: u64) -> Error {
+        SizeMismatch { name: name.to_owned(), expected, actual }.into()
+    }
+
+    /// Constructs an `InvalidUrl` error
+    pub fn invalid_url(line: usize, url: &str, reason: &str) -> Error {
+        InvalidUrl {
+            line,
+            url: url.to_owned(),
+            reason: reason.to_owned()
+        }.into()
+    }
+
+    /// Constructs an `InvalidMetalink` error
+    pub fn invalid_metalink(reason: &str) -> Error {
+        InvalidMetalink(reason.to_owned()).into()
+    }
+
+    /// Constructs an `InvalidAria2` error
+    pub fn invalid_aria2(reason: &str) -> Error {
+        InvalidAria2(reason.to_owned()).into()
+    }
+
+    /// Constructs a `NoFileName` error
+    pub fn no_file_name(url: &::reqwest::Url) -> Error {
+        NoFileName(url.to_string()).into()
+    }
+
+    /// Constructs a `ResolvedConflict` error
+    pub fn resolved_conflict(path: &str, first: &str, second: &str) -> Error {
+        ResolvedConflict {
+            path: path.to_owned(),
+            first: first.to_owned(),
+            second: second.to_owned()
+        }.into()
+    }
+
+    /// Constructs an `InvalidPattern` error
+    pub fn invalid_pattern(key: &str, reason: &str) -> Error {
+        InvalidPattern { key: key.to_owned(), reason: reason.to_owned() }.into()
+    }
+
+    /// Constructs a `DuplicateDestination` error
+    pub fn duplicate_destination(archive: &str, path: &std::path::Path) -> Error {
+        DuplicateDestination {
+            archive: archive.to_owned(),
+            path: path.display().to_string()
+        }.into()
+    }
+
+    /// Constructs an `InvalidTemplate` error
+    pub fn invalid_template(reason: &str) -> Error {
+        InvalidTemplate(reason.to_owned()).into()
+    }
+
+    /// Constructs a `NotInTopDir` error
+    pub fn not_in_top_dir(name: &str, top: &str) -> Error {
+        NotInTopDir { name: name.to_owned(), top: top.to_owned() }.into()
+    }
+
+    /// Constructs a `LimitExceeded` error
+    pub fn limit_exceeded(archive: &str, reason: &str) -> Error {
+        LimitExceeded { archive: archive.to_owned(), reason: reason.to_owned() }.into()
+    }
+
+    /// Constructs an `UnsafePath` error
+    pub fn unsafe_path(path: &std::path::Path, dir: &std::path::Path) -> Error {
+        UnsafePath {
+            path: path.display().to_string(),
+            dir: dir.display().to_string()
+        }.into()
+    }
+
+    /// Constructs an `UnsafeLink` error
+    pub fn unsafe_link(path: &std::path::Path, target: &std::path::Path,
+                       dir: &std::path::Path) -> Error {
+        UnsafeLink {
+            path: path.display().to_string(),
+            target: target.display().to_string(),
+            dir: dir.display().to_string()
+        }.into()
+    }
+
+    /// Constructs a `DownloadsFailed` error, given the failed files and the
+    /// number of files that were attempted to be downloaded.
+    pub fn download_failed(files: Vec<(std::path::PathBuf, String)>, total: usize) -> Error {
+        DownloadsFailed { files, total }.into()
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    /// The category of an error. Each kind corresponds to a distinct exit code,
+    /// so scripts can react differently to different failures.
+    ///
+    /// Attach a kind to an error with `failure::ResultExt::context`.
+    pub enum ErrorKind {
+        /// The template could not be read or is invalid.
+        Template,
+        /// Some, but not all, of the downloads failed.
+        PartialDownload,
+        /// All of the downloads failed.
+        TotalDownload,
+        /// A file did not match its expected checksum.
+        Verification,
+        /// An archive could not be extracted.
+        Extraction,
+        /// An I/O error occurred.
+        Io,
+        /// Any other error.
+        Other
+    }
+
+    impl ErrorKind {
+        /// Returns the exit code that the program should exit with when an
+        /// error of this kind occurs. These codes are stable.
+        pub fn exit_code(self) -> i32 {
+            match self {
+                ErrorKind::Other => 1,
+                ErrorKind::Template => 2,
+                ErrorKind::PartialDownload => 3,
+                ErrorKind::TotalDownload => 4,
+                ErrorKind::Verification => 5,
+                ErrorKind::Extraction => 6,
+                ErrorKind::Io => 7
+            }
+        }
+    }
+
+    impl std::fmt::Display for ErrorKind {
+        fn fmt(&self, ft: &mut std::fmt::Formatter) -> std::fmt::Result {
+            let desc = match self {
+                ErrorKind::Template => "invalid template",
+                ErrorKind::PartialDownload => "some downloads failed",
+                ErrorKind::TotalDownload => "all downloads failed",
+                ErrorKind::Verification => "verification failed",
+                ErrorKind::Extraction => "extraction failed",
+                ErrorKind::Io => "I/O error",
+                ErrorKind::Other => "error"
+            };
+            write!(ft, "{}", desc)
+        }
+    }
+
+    /// Determines the kind of the given error. A checksum or size mismatch
+    /// anywhere in the chain of causes is always a verification error, and an
+    /// I/O error anywhere in the chain is an I/O error unless the error has a
+    /// kind.
+    pub fn kind(err: &Error) -> ErrorKind {
+        let is_mismatch = |e: &dyn failure::Fail| {
+            e.downcast_ref::<ChecksumMismatch>().is_some()
+                || e.downcast_ref::<SizeMismatch>().is_some()
+        };
+        if err.iter_chain().any(is_mismatch) {
+            ErrorKind::Verification
+        } else if let Some(ctx) = err.downcast_ref::<failure::Context<ErrorKind>>() {
+            *ctx.get_context()
+        } else if let Some(failed) = err.downcast_ref::<DownloadsFailed>() {
+            if failed.files.len() < failed.total {
+                ErrorKind::PartialDownload
+            } else {
+                ErrorKind::TotalDownload
+            }
+        } else if err.iter_chain().any(|e| e.downcast_ref::<std::io::Error>().is_some()) {
+            ErrorKind::Io
+        } else {
+            ErrorKind::Other
+        }
     }
 }
@@ -1,19 +1,30 @@
+use failure::ResultExt;
 use futures::{Future, Stream};
+use futures::future::Loop;
 use reqwest::r#async as req;
 use std::fs;
 use std::io;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{Receiver, SyncSender};
 use std::time::{Duration, Instant};
 use structopt::StructOpt;
+use structopt::clap;
 use tokio::prelude::FutureExt;
 use tokio::prelude::StreamExt;
 
 use tempget::template;
 use tempget::errors;
+use tempget::errors::ErrorKind;
 use tempget::cli::*;
-use tempget::template::ExtractInfo;
+use tempget::init::InputFormat;
+use tempget::template::{ExtractInfo, ExtractTarget, FileMode, Format, StripComponents,
+                        TemplateSource};
+use tempget::decompress::DecompressWriter;
+use tempget::extract::{EntryKind, EntryPath, EntryWriter, ExtractLimits, LimitTracker, LimitedReader,
+                       NestedArchive, StreamExtractor};
+use tempget::tar::TarParser;
 
 /// Application entry point.
 fn main() {
@@ -23,43 +34,408 @@ fn main() {
         Ok(()) => {},
         Err(err) => {
             eprintln!("Error: {}", err);
-            std::process::exit(1);
+            for cause in err.iter_causes() {
+                eprintln!("Caused by: {}", cause);
+            }
+            std::process::exit(errors::kind(&err).exit_code());
         }
     }
 }
 
 /// Run the program with the given options.
 fn run(options: &CliOptions) -> errors::Result<()> {
-    let templ = template::Template::from_file(&options.template_file)?;
+    match &options.command {
+        Some(Command::Fetch(cmd)) => run_fetch(&cmd.template_file, &cmd.options),
+        Some(Command::Check(cmd)) => run_check(cmd),
+        Some(Command::Init(cmd)) => run_init(cmd),
+        Some(Command::Export(cmd)) => run_export(cmd),
+        None => match &options.template_file {
+            Some(template_file) => run_fetch(template_file, &options.fetch),
+            None => {
+                let msg = "a template file or a subcommand must be given";
+                clap::Error::with_description(msg, clap::ErrorKind::MissingRequiredArgument)
+                    .exit()
+            }
+        }
+    }
+}
 
-    let final_state = do_fetch(options, &templ)?;
-    let failed = final_state.failed();
-    if failed.len() > 0 {
-        let files: Vec<(PathBuf, String)> = failed.iter()
+/// Download and extract the files specified by the template.
+fn run_fetch(template_file: &TemplateSource, options: &FetchOptions) -> errors::Result<()> {
+    let mut templ = template::Template::load(template_file, &options.template.load_options())
+        .context(ErrorKind::Template)?;
+    options.filter().apply(&mut templ);
+    resolve_entries(options.timeout, &mut templ)?;
+    if options.dry_run {
+        return do_dry_run(options, &templ);
+    }
+
+    let mut final_state = do_fetch(options, &templ)?;
+    let optional_files: HashSet<PathBuf> = templ.retrieve.iter()
+        .filter(|(_, info)| info.is_optional())
+        .map(|(path_str, _)| PathBuf::from(path_str))
+        .collect();
+    let (optional_failed, failed): (Vec<_>, Vec<_>) =
+        final_state.failed().iter()
             .map(|(id, err)| {
                 let p = final_state.get_path(id).unwrap().to_owned();
-                let e = err.to_string();
+                // Errors that have been given a kind are described by their
+                // cause instead.
+                let e = match err.downcast_ref::<failure::Context<ErrorKind>>() {
+                    Some(_) => err.iter_causes().next()
+                        .map_or_else(|| err.to_string(), |cause| cause.to_string()),
+                    None => err.to_string()
+                };
                 (p, e)
             })
+            .partition(|(p, _)| optional_files.contains(p));
+    for (path, err) in &optional_failed {
+        eprintln!("Warning: optional file {} failed to download: {}", path.display(), err);
+        let archive = path.to_string_lossy();
+        if templ.extract.remove(archive.as_ref()).is_some() {
+            eprintln!("Warning: skipping extraction of {}", archive);
+        }
+    }
+    if failed.len() > 0 {
+        let required_total = final_state.file_info.values()
+            .filter(|(p, _)| !optional_files.contains(p))
+            .count();
+        // Archives that are extracted while they are downloaded are verified
+        // and extracted as part of the download.
+        let kinds: Vec<_> = final_state.failed().iter()
+            .filter(|(id, _)| !optional_files.contains(final_state.get_path(id).unwrap()))
+            .map(|(_, err)| errors::kind(err))
+            .collect();
+        let err = errors::download_failed(failed, required_total);
+        return if kinds.contains(&ErrorKind::Verification) {
+            Err(err.context(ErrorKind::Verification).into())
+        } else if kinds.contains(&ErrorKind::Extraction) {
+            Err(err.context(ErrorKind::Extraction).into())
+        } else {
+            Err(err)
+        };
+    }
+    // Each failure has already been reported, so only the first is returned.
+    if let Some((path, err)) = final_state.take_extract_failures().into_iter().next() {
+        let err = err.context(format!("could not extract {}", path.display()));
+        return Err(failure::Error::from(err).context(ErrorKind::Extraction).into());
+    }
+    verify_files(&templ, !options.no_extract)?;
+    set_file_modes(&templ)
+}
+
+/// Resolve the entries that can only be downloaded after contacting a server:
+/// entries that refer to Metalink documents are replaced with the files that
+/// the documents describe, and entries whose paths end in `/` are given a file
+/// name. Archives in the `extract` section follow their entries. Optional
+/// entries that cannot be resolved are skipped.
+fn resolve_entries(timeout: u64, templ: &mut template::Template) -> errors::Result<()> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(timeout))
+        .build()?;
+    let mut pending: Vec<String> = templ.retrieve.iter()
+        .filter(|(path_str, info)| {
+            path_str.ends_with('/') || tempget::import::is_metalink_url(info.url())
+        })
+        .map(|(path_str, _)| path_str.clone())
+        .collect();
+    pending.sort();
+    // The original paths of the entries that have been resolved.
+    let mut origins = HashMap::<String, String>::new();
+    for path_str in pending {
+        let info = templ.retrieve.remove(&path_str).unwrap();
+        match resolve_entry(&client, &path_str, &info) {
+            Ok((resolved_path, resolved)) => {
+                if templ.retrieve.contains_key(&resolved_path) {
+                    let other = origins.get(&resolved_path).unwrap_or(&resolved_path);
+                    return Err(errors::resolved_conflict(&resolved_path, other, &path_str));
+                }
+                if let Some(extract) = templ.extract.remove(&path_str) {
+                    templ.extract.insert(resolved_path.clone(), extract);
+                }
+                templ.retrieve.insert(resolved_path.clone(), resolved);
+                origins.insert(resolved_path, path_str);
+            },
+            Err(err) if info.is_optional() => {
+                eprintln!("Warning: skipping optional file {}: {}", path_str, err);
+                for cause in err.iter_causes() {
+                    eprintln!("Caused by: {}", cause);
+                }
+                if templ.extract.remove(&path_str).is_some() {
+                    eprintln!("Warning: skipping extraction of {}", path_str);
+                }
+            },
+            Err(err) => return Err(err)
+        }
+    }
+    Ok(())
+}
+
+/// Resolve a single entry, returning its new path and options.
+fn resolve_entry(client: &reqwest::Client, path_str: &str, info: &template::RetrieveInfo)
+                 -> errors::Result<(String, template::RetrieveInfo)> {
+    let is_dir = path_str.ends_with('/');
+    if tempget::import::is_metalink_url(info.url()) {
+        let (name, resolved) = tempget::import::resolve_metalink(client, path_str, info)
+            .with_context(|_| format!("could not read Metalink document {}", info.url()))?;
+        let resolved_path = if is_dir { format!("{}{}", path_str, name) } else { path_str.to_owned() };
+        return Ok((resolved_path, resolved));
+    }
+
+    // Use the first URL that responds, falling back to the mirrors.
+    let mut last_err = None;
+    for url in std::iter::once(info.url()).chain(info.mirrors()) {
+        match tempget::fetcher::resolve_file_name(client, url) {
+            Ok(name) => return Ok((format!("{}{}", path_str, name), info.clone())),
+            Err(err) => last_err = Some(err)
+        }
+    }
+    let err = last_err.expect("entry has no URLs");
+    Err(err.context(format!("could not determine the file name for {}", path_str)).into())
+}
+
+/// Print what would be downloaded and extracted, without writing anything.
+fn do_dry_run(options: &FetchOptions, templ: &template::Template) -> errors::Result<()> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(options.timeout))
+        .build()?;
+    let mut retrieve: Vec<_> = templ.retrieve.iter().collect();
+    retrieve.sort_by(|a, b| a.0.cmp(b.0));
+    for (path_str, info) in retrieve {
+        if Path::new(path_str).exists() {
+            println!("Would skip {} (already exists)", path_str);
+            continue;
+        }
+        let extract = templ.extract.get(path_str).filter(|_| !options.no_extract);
+        if let Some(extract_info) = extract.filter(|i| !i.keeps_archive()) {
+            if tempget::extract::is_extracted(path_str, extract_info) {
+                println!("Would skip {} (already extracted)", path_str);
+                continue;
+            }
+            if extract_info.partial() {
+                println!("Would read the files to extract from {} instead of downloading {}",
+                         info.url(), path_str);
+                continue;
+            }
+        }
+        let size = match tempget::fetcher::head_size(&client, info.url()).or(info.size()) {
+            Some(size) => ProgressState::display_bytes(size),
+            None => "unknown size".to_owned()
+        };
+        let optional = if info.is_optional() { ", optional" } else { "" };
+        let decompress = if extract.is_some_and(ExtractInfo::stream) {
+            ", extracted while downloading"
+        } else if info.decompress(path_str) {
+            ", decompressed"
+        } else {
+            ""
+        };
+        println!("Would download {} to {} ({}{}{})",
+                 info.url(), path_str, size, optional, decompress);
+    }
+
+    if options.no_extract {
+        return Ok(());
+    }
+    let mut extract: Vec<_> = templ.extract.iter().collect();
+    extract.sort_by(|a, b| a.0.cmp(b.0));
+    for (archive, info) in extract {
+        let archive_path = Path::new(archive);
+        if !archive_path.exists() && !info.keeps_archive()
+            && tempget::extract::is_extracted(archive, info) {
+            println!("Would skip extracting {} (already extracted)", archive);
+            continue;
+        }
+        if !archive_path.exists() || tempget::tar::is_tar(archive) {
+            // The archive has not been downloaded yet, or is a tar archive,
+            // which would have to be read in full to list its contents.
+            print_planned_extraction(archive, info);
+            continue;
+        }
+
+        let file = fs::File::open(archive_path)?;
+        let mut zip_archive = zip::read::ZipArchive::new(file)?;
+        for (name, dest, _) in archive_entries(archive, &mut zip_archive, info)? {
+            let dest_path = dest.joined();
+            if dest_path.exists() {
+                println!("Would skip {} (already exists)", dest_path.to_string_lossy());
+            } else {
+                println!("Would extract {} in {} to {}",
+                         name, archive, dest_path.to_string_lossy());
+            }
+        }
+        print_planned_nested(archive, info);
+    }
+    Ok(())
+}
+
+/// Prints what would be extracted from an archive whose contents are not
+/// known, including the archives nested inside of it.
+fn print_planned_extraction(archive: &str, info: &ExtractInfo) {
+    match info.target() {
+        ExtractTarget::Directory(d) => {
+            let strip = match info.strip_components() {
+                Some(StripComponents::Count(n)) => {
+                    format!(", removing {} leading components", n)
+                },
+                Some(StripComponents::Auto) => {
+                    ", removing any top-level directory".to_owned()
+                },
+                None => String::new()
+            };
+            println!("Would extract all files in {} to {}{}", archive, d, strip);
+        },
+        ExtractTarget::Mapping(files) => {
+            let mut files: Vec<_> = files.iter().collect();
+            files.sort_by(|a, b| a.0.cmp(b.0));
+            for (name, dest) in files {
+                match dest.path() {
+                    Some(path) if tempget::extract::is_pattern(name) => {
+                        println!("Would extract files matching {} in {} to {}",
+                                 name, archive, path);
+                    },
+                    Some(path) => println!("Would extract {} in {} to {}", name, archive, path),
+                    None => {}
+                }
+            }
+            print_planned_nested(archive, info);
+        }
+    }
+}
+
+/// Prints what would be extracted from the archives nested inside of an
+/// archive.
+fn print_planned_nested(archive: &str, info: &ExtractInfo) {
+    if let ExtractTarget::Mapping(files) = info.target() {
+        let mut nested: Vec<_> = files.iter()
+            .filter_map(|(name, dest)| dest.nested().map(|nested| (name, nested)))
             .collect();
-        Err(errors::download_failed(files))
-    } else if !options.no_extract {
-        do_extract(templ)
+        nested.sort_by(|a, b| a.0.cmp(b.0));
+        for (name, nested) in nested {
+            print_planned_extraction(&template::nested_name(name, archive), nested);
+        }
+    }
+}
+
+/// Verify the sizes and checksums of the retrieved files that have them.
+/// Files that already existed are verified as well. If `skip_archives` is
+/// true, archives in the `extract` section are skipped, since they are
+/// verified before they are extracted.
+fn verify_files(templ: &template::Template, skip_archives: bool) -> errors::Result<()> {
+    let mut retrieve: Vec<_> = templ.retrieve.iter()
+        .filter(|(path_str, _)| !skip_archives || !templ.extract.contains_key(*path_str))
+        .collect();
+    retrieve.sort_by(|a, b| a.0.cmp(b.0));
+    for (path_str, info) in retrieve {
+        verify_file(path_str, info)?;
+    }
+    Ok(())
+}
+
+/// Verify the size and checksum of a retrieved file, if it exists and they
+/// are given.
+fn verify_file(path_str: &str, info: &template::RetrieveInfo) -> errors::Result<()> {
+    let path = Path::new(path_str);
+    if !path.exists() {
+        return Ok(());
+    }
+    if let Some(expected) = info.size() {
+        let actual = fs::metadata(path)?.len();
+        if actual != expected {
+            return Err(errors::size_mismatch(path_str, expected, actual));
+        }
+    }
+    if let Some(expected) = info.sha256() {
+        let actual = tempget::checksum::sha256_file(path)?;
+        if !actual.eq_ignore_ascii_case(expected) {
+            return Err(errors::checksum_mismatch(path_str, expected, &actual));
+        }
+    }
+    Ok(())
+}
+
+/// Check the template for problems and print them.
+fn run_check(cmd: &CheckCommand) -> errors::Result<()> {
+    let templ = template::Template::load(&cmd.template_file, &cmd.template.load_options())
+        .context(ErrorKind::Template)?;
+    let problems = tempget::check::check_template(&templ);
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    let error_count = problems.iter().filter(|p| p.is_error()).count();
+    println!("{} error(s), {} warning(s)", error_count, problems.len() - error_count);
+    if error_count > 0 {
+        Err(errors::check_failed(error_count).context(ErrorKind::Template).into())
     } else {
         Ok(())
     }
 }
 
+/// Create a template from a list of URLs or another input format and write it
+/// to the output file, or print it.
+fn run_init(cmd: &InitCommand) -> errors::Result<()> {
+    let input = cmd.input.read()
+        .with_context(|_| format!("could not read {}", cmd.input))?;
+    let templ = match cmd.from.unwrap_or_else(|| InputFormat::detect(&cmd.input)) {
+        InputFormat::List => {
+            let urls = tempget::init::parse_url_list(&input)?;
+            let options = tempget::init::InitOptions {
+                checksums: cmd.checksums,
+                timeout: Duration::from_secs(cmd.timeout)
+            };
+            tempget::init::template_from_urls(&urls, &options)?
+        },
+        InputFormat::Aria2 => tempget::import::from_aria2(&input)?,
+        InputFormat::Metalink => tempget::import::from_metalink(&input)?
+    };
+    let format = cmd.format
+        .or_else(|| cmd.output.as_ref().and_then(|p| Format::from_path(p)))
+        .unwrap_or(Format::Toml);
+    let contents = match format {
+        Format::Toml => templ.to_toml()?,
+        // JSON is valid YAML.
+        Format::Json | Format::Yaml => templ.to_json()? + "\n"
+    };
+    write_output(cmd.output.as_deref(), &contents)
+}
+
+/// Convert a template into a script or another downloader's input format.
+fn run_export(cmd: &ExportCommand) -> errors::Result<()> {
+    let mut templ = template::Template::load(&cmd.template_file, &cmd.load_options())
+        .context(ErrorKind::Template)?;
+    resolve_entries(cmd.timeout, &mut templ)?;
+    for message in tempget::export::unsupported(&templ, cmd.format) {
+        eprintln!("Warning: {}", message);
+    }
+    let contents = tempget::export::export(&templ, cmd.format, &cmd.template_file.to_string());
+    write_output(cmd.output.as_deref(), &contents)
+}
+
+/// Write the contents to the output file, or print them if there is no output
+/// file. Existing files are never overwritten.
+fn write_output(output: Option<&Path>, contents: &str) -> errors::Result<()> {
+    match output {
+        Some(path) => {
+            fs::OpenOptions::new().write(true).create_new(true).open(path)
+                .and_then(|mut file| io::Write::write_all(&mut file, contents.as_bytes()))
+                .with_context(|_| format!("could not write {}", path.display()))?;
+            println!("Wrote {}", path.display());
+        },
+        None => print!("{}", contents)
+    }
+    Ok(())
+}
+
 /// Download the files specified in the `retrieve` section of the template and
 /// display the progress. Returns the final `ProgressState` containing all file
 /// download progress information.
-fn do_fetch(options: &CliOptions, templ: &template::Template) -> errors::Result<ProgressState> {
+fn do_fetch(options: &FetchOptions, templ: &template::Template) -> errors::Result<ProgressState> {
     let timeout_dur = Duration::from_secs(options.timeout);
     let mut runtime = tokio::runtime::Builder::new().build()?;
     let client = req::Client::builder()
         .connect_timeout(timeout_dur)
         .build()?;
-    let mut requests = Vec::<(usize, PathBuf, req::Request)>::new();
+    let mut requests = Vec::<(usize, PathBuf, Vec<req::Request>, Output)>::new();
     let mut idx: usize = 0;
     for (path_str, request) in tempget::fetcher::get_template_requests(&templ) {
         let path = Path::new(&path_str);
@@ -67,12 +443,63 @@ fn do_fetch(options: &CliOptions, templ: &template::Template) -> errors::Result<
             println!("{} exists, skipping", path_str);
             continue;
         }
-        requests.push((idx, path.to_owned(), request));
+        let extract = templ.extract.get(&path_str).filter(|_| !options.no_extract);
+        // Archives that are not kept are only downloaded again if their files
+        // are missing.
+        if let Some(info) = extract.filter(|i| !i.keeps_archive()) {
+            if tempget::extract::is_extracted(&path_str, info) {
+                println!("{} has already been extracted, skipping", path_str);
+                continue;
+            }
+            if info.partial() {
+                // The files are read from the server when extracting.
+                continue;
+            }
+        }
+        let retrieve_info = &templ.retrieve[&path_str];
+        let output = match extract {
+            Some(info) if info.stream() => Output::Extract(Arc::new(StreamOptions {
+                info: info.clone(),
+                umask: options.umask,
+                limits: options.limits(),
+                size: retrieve_info.size(),
+                sha256: retrieve_info.sha256().map(str::to_owned)
+            })),
+            _ if retrieve_info.decompress(&path_str) => Output::Decompressed,
+            _ => Output::File
+        };
+        requests.push((idx, path.to_owned(), request, output));
         idx += 1;
     }
 
+    // Archives are extracted as soon as they are downloaded. Archives that
+    // are not downloaded are extracted right away.
+    let mut ready = Vec::new();
+    let mut waiting = HashMap::<usize, ExtractJob>::new();
+    if !options.no_extract {
+        let mut extract: Vec<_> = templ.extract.iter().collect();
+        extract.sort_by(|a, b| a.0.cmp(b.0));
+        for (id, (archive, info)) in extract.into_iter().enumerate() {
+            let job = ExtractJob {
+                id,
+                archive: archive.clone(),
+                info: info.clone(),
+                retrieve: templ.retrieve.get(archive).cloned(),
+                umask: options.umask,
+                limits: options.limits(),
+                timeout: options.timeout
+            };
+            match requests.iter().find(|(_, path, _, _)| path == Path::new(archive)) {
+                // Streamed archives are extracted by the download.
+                Some((_, _, _, Output::Extract(_))) => {},
+                Some((idx, _, _, _)) => { waiting.insert(*idx, job); },
+                None => ready.push(job)
+            }
+        }
+    }
+
     let file_info: HashMap<usize, _> = requests.iter()
-        .map(|(idx, p, req)| (idx.clone(), (p.clone(), req.url().clone())))
+        .map(|(idx, p, reqs, _)| (idx.clone(), (p.clone(), reqs[0].url().clone())))
         .collect();
     // `sync_channel` instead of `channel` since status message order is
     // important
@@ -84,42 +511,28 @@ fn do_fetch(options: &CliOptions, templ: &template::Template) -> errors::Result<
 
     // TODO: refactor into separate function (Vec<Requests> -> Stream<Vec<()>>)
     let tasks = futures::stream::iter_ok(requests)
-        .map(move |(idx, path, request)| {
+        .map(move |(idx, path, requests, output)| {
             let prog_tx = prog_tx.clone();
             prog_tx.send(DownloadStatus::Init(idx)).unwrap();
             let idx_err = idx.clone();
             let err_tx = prog_tx.clone();
-            let timeout_secs = timeout_dur.as_secs();
-            client
-                .execute(request)
-                .timeout(timeout_dur)
-                .map_err(move |timer_err| {
-                    let err_res: errors::Error =
-                        if let Some(e) = timer_err.into_inner() {
-                            e.into()
-                        } else {
-                            errors::timeout(timeout_secs)
-                        };
-                    err_res
-                })
-                .and_then(|response| {
-                    let status = response.status();
-                    if !status.is_success() {
-                        Err(errors::status_code(status))
-                    } else {
-                        Ok(response)
-                    }
-                })
-                .and_then(move |response| {
-                    let size_opt = response.headers()
-                        .get(reqwest::header::CONTENT_LENGTH)
-                        .and_then(|ct_len| ct_len.to_str().ok())
-                        .and_then(|ct_len| ct_len.parse().ok());
-
-                    prog_tx.send(DownloadStatus::Start(idx, size_opt)).unwrap();
-
-                    write_file(&path, response, idx, prog_tx, timeout_dur.clone())
-                })
+            let client = client.clone();
+            // Try each mirror in turn until one of them succeeds.
+            futures::future::loop_fn(requests.into_iter(), move |mut requests| {
+                let request = requests.next().expect("no requests for file");
+                let prog_tx = prog_tx.clone();
+                download(&client, request, &path, output.clone(), idx, prog_tx.clone(),
+                         timeout_dur)
+                    .then(move |res| match (res, requests.as_slice().first()) {
+                        (Ok(_), _) => Ok(Loop::Break(())),
+                        (Err(err), Some(next)) => {
+                            prog_tx.send(DownloadStatus::Retry(idx, err, next.url().clone()))
+                                .unwrap();
+                            Ok(Loop::Continue(requests))
+                        },
+                        (Err(err), None) => Err(err)
+                    })
+            })
                 .then(move |res| match res {
                     Ok(_) => Ok(()),
                     Err(err) => {
@@ -136,17 +549,31 @@ fn do_fetch(options: &CliOptions, templ: &template::Template) -> errors::Result<
     let f = tasks.collect().map(|_| ());
     runtime.spawn(f);
 
-    let final_state = block_progress(file_info, prog_rx)?;
+    let mut state = ProgressState::new(file_info);
+    let pool = ExtractPool::new(options.parallelism.min(ready.len() + waiting.len()),
+                                keep_alive.clone());
+    for job in ready {
+        state.add_extraction(job.id, PathBuf::from(&job.archive));
+        pool.run(job);
+    }
+    let final_state = block_progress(state, prog_rx, |idx, state| {
+        if let Some(job) = waiting.remove(&idx) {
+            state.add_extraction(job.id, PathBuf::from(&job.archive));
+            pool.run(job);
+        }
+    })?;
+    drop(pool);
     drop(keep_alive);
     runtime.shutdown_on_idle().wait().expect("Could not shutdown tokio runtime");
     Ok(final_state)
 }
 
-/// Blocks the current thread and renders download progress until all files have
-/// been downloaded.
-fn block_progress(file_info: HashMap<usize, (PathBuf, reqwest::Url)>,  rx: Receiver<DownloadStatus>)
-                  -> io::Result<ProgressState> {
-    let mut state = ProgressState::new(file_info);
+/// Blocks the current thread and renders download and extraction progress until
+/// all files have been downloaded and extracted. `on_finish` is called with the
+/// index of each file once it has been downloaded.
+fn block_progress<F>(mut state: ProgressState, rx: Receiver<DownloadStatus>, mut on_finish: F)
+                     -> io::Result<ProgressState>
+    where F: FnMut(usize, &mut ProgressState) {
     // Throttle rendering so we don't spend so much time reporting progress
     let mut last_render = std::time::Instant::now();
     let mut renderer = ProgressRender::stderr();
@@ -183,6 +610,55 @@ fn block_progress(file_info: HashMap<usize, (PathBuf, reqwest::Url)>,  rx: Recei
                 renderer.clear()?;
                 let download_path = state.get_path(&idx).unwrap().display();
                 renderer.message(format!("Finished downloading {}", download_path))?;
+                on_finish(idx, &mut state);
+                renderer.println_multi(&state.render())?;
+                renderer.flush()?;
+            },
+            Ok(Retry(idx, err, mirror)) => {
+                state.mark_retry(&idx);
+                renderer.clear()?;
+                let download_path = state.get_path(&idx).unwrap().display();
+                renderer.message(format!("Failed to download {}: {}; trying {}",
+                                         download_path, err, mirror))?;
+                renderer.println_multi(&state.render())?;
+                renderer.flush()?;
+            },
+            Ok(Message(_, message)) => {
+                renderer.clear()?;
+                renderer.message(message)?;
+                renderer.println_multi(&state.render())?;
+                renderer.flush()?;
+            },
+            Ok(ExtractStart(id, size_opt)) => {
+                state.mark_extract_start(&id, size_opt);
+                renderer.clear()?;
+                let archive = state.get_extract_path(&id).unwrap().display();
+                renderer.message(format!("Extracting {}", archive))?;
+                renderer.println_multi(&state.render())?;
+                renderer.flush()?;
+            },
+            Ok(ExtractProgress(id, size, timestamp)) => {
+                state.inc_extract_progress(id, size, &timestamp);
+                let now = std::time::Instant::now();
+                if now - last_render > std::time::Duration::from_millis(200) {
+                    last_render = now;
+                    renderer.clear()?;
+                    renderer.println_multi(&state.render())?;
+                    renderer.flush()?;
+                }
+            },
+            Ok(ExtractFinish(id)) => {
+                state.mark_extract_finished(&id);
+                renderer.clear()?;
+                renderer.println_multi(&state.render())?;
+                renderer.flush()?;
+            },
+            Ok(ExtractFailed(id, err)) => {
+                state.mark_extract_failed(&id, err);
+                renderer.clear()?;
+                let archive = state.get_extract_path(&id).unwrap().display();
+                let err = state.get_extract_error(&id).unwrap();
+                renderer.message(format!("Failed to extract {}: {}", archive, err))?;
                 renderer.println_multi(&state.render())?;
                 renderer.flush()?;
             },
@@ -202,81 +678,610 @@ fn block_progress(file_info: HashMap<usize, (PathBuf, reqwest::Url)>,  rx: Recei
     Ok(state)
 }
 
-/// Extract all files specified in the template file. Note that extraction is
-/// currently synchronous.
-fn do_extract(templ: template::Template) -> errors::Result<()> {
-    for (archive, info) in &templ.extract {
-        let file = fs::File::open(Path::new(archive))?;
-        let mut zip_archive = zip::read::ZipArchive::new(file)?;
-        let mut extract_files = Vec::<(usize, PathBuf)>::new();
-        match info {
-            ExtractInfo::Directory(d) => {
-                let dest_dir = Path::new(d);
-                for i in 0..zip_archive.len() {
-                    let f = zip_archive.by_index(i)?;
-                    if !f.name().ends_with("/") {
-                        // Don't add directories
-                        extract_files.push((i, dest_dir.join(Path::new(f.name()))));
+/// Set the permissions of the retrieved files that have a `mode` or are
+/// `executable`. Files that already existed are updated as well.
+fn set_file_modes(templ: &template::Template) -> errors::Result<()> {
+    for (path_str, info) in &templ.retrieve {
+        let path = Path::new(path_str);
+        if let (Some(mode), true) = (info.file_mode(), path.exists()) {
+            tempget::extract::set_file_mode(path, mode)
+                .with_context(|_| format!("could not set the permissions of {}", path_str))?;
+        }
+    }
+    Ok(())
+}
+
+/// An archive to extract, along with the options needed to extract it.
+struct ExtractJob {
+    /// The id of the extraction, used to report its progress.
+    id: usize,
+    archive: String,
+    info: ExtractInfo,
+    /// The entry that retrieves the archive, if any.
+    retrieve: Option<template::RetrieveInfo>,
+    umask: u32,
+    limits: ExtractLimits,
+    timeout: u64
+}
+
+/// A pool of threads that extract archives, so that archives are extracted
+/// while other files are downloaded. The threads exit once the pool is
+/// dropped and all of the jobs have run.
+struct ExtractPool {
+    jobs: Option<std::sync::mpsc::Sender<ExtractJob>>,
+    workers: Vec<std::thread::JoinHandle<()>>
+}
+
+impl ExtractPool {
+    /// Starts `size` threads (at least one), which report progress to
+    /// `prog_tx`.
+    fn new(size: usize, prog_tx: SyncSender<DownloadStatus>) -> Self {
+        let (jobs, receiver) = std::sync::mpsc::channel::<ExtractJob>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..size.max(1))
+            .map(|_| {
+                let receiver = receiver.clone();
+                let prog_tx = prog_tx.clone();
+                std::thread::spawn(move || loop {
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => run_extract_job(job, &prog_tx),
+                        Err(_) => break
                     }
-                }
+                })
+            })
+            .collect();
+        ExtractPool { jobs: Some(jobs), workers }
+    }
+
+    /// Queues the job to run on the next free thread.
+    fn run(&self, job: ExtractJob) {
+        self.jobs.as_ref().unwrap().send(job).expect("extraction threads exited");
+    }
+}
+
+impl Drop for ExtractPool {
+    fn drop(&mut self) {
+        drop(self.jobs.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Runs an extraction job, reporting whether it finished or failed. A panic
+/// is reported as a failure, so that progress tracking does not wait for the
+/// job forever.
+fn run_extract_job(job: ExtractJob, prog_tx: &SyncSender<DownloadStatus>) {
+    let id = job.id;
+    let reporter = ExtractReporter { id, tx: prog_tx.clone(), progress: true };
+    let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        extract_archive(&job, &reporter)
+    }));
+    let status = match res {
+        Ok(Ok(())) => DownloadStatus::ExtractFinish(id),
+        Ok(Err(err)) => DownloadStatus::ExtractFailed(id, err),
+        Err(_) => DownloadStatus::ExtractFailed(id, failure::err_msg("extraction panicked"))
+    };
+    prog_tx.send(status).unwrap();
+}
+
+/// Reports the progress of an extraction to the progress renderer.
+#[derive(Clone)]
+struct ExtractReporter {
+    id: usize,
+    tx: SyncSender<DownloadStatus>,
+    /// Whether progress is reported. Nested archives and archives that are
+    /// extracted while they are downloaded only report messages.
+    progress: bool
+}
+
+impl ExtractReporter {
+    /// Returns a reporter for an archive nested inside of this one, which
+    /// does not replace the progress of this archive.
+    fn nested(&self) -> Self {
+        ExtractReporter { progress: false, ..self.clone() }
+    }
+
+    /// Reports that the extraction has started, with the number of bytes to
+    /// extract.
+    fn start(&self, size: Option<u64>) {
+        if self.progress {
+            self.tx.send(DownloadStatus::ExtractStart(self.id, size)).unwrap();
+        }
+    }
+
+    /// Reports the number of bytes extracted since the last report.
+    fn progress(&self, size: u64) {
+        if self.progress {
+            self.tx.send(DownloadStatus::ExtractProgress(self.id, size, Instant::now())).unwrap();
+        }
+    }
+
+    /// Prints a message, such as the name of a file that was extracted.
+    fn message(&self, message: String) {
+        self.tx.send(DownloadStatus::Message(self.id, message)).unwrap();
+    }
+
+    /// Returns a function that prints messages, for `StreamExtractor`.
+    fn messages(&self) -> Box<dyn FnMut(String) + Send> {
+        let reporter = self.clone();
+        Box::new(move |message| reporter.message(message))
+    }
+}
+
+/// The number of bytes that a `ProgressReader` reads between reports.
+const PROGRESS_INTERVAL: u64 = 256 * 1024;
+
+/// A reader that reports the number of bytes read from it as extraction
+/// progress.
+struct ProgressReader<'a, R> {
+    inner: R,
+    reporter: &'a ExtractReporter,
+    /// The number of bytes read since the last report.
+    unreported: u64
+}
+
+impl<'a, R: io::Read> ProgressReader<'a, R> {
+    fn new(inner: R, reporter: &'a ExtractReporter) -> Self {
+        ProgressReader { inner, reporter, unreported: 0 }
+    }
+}
+
+impl<'a, R: io::Read> io::Read for ProgressReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.unreported += n as u64;
+        if self.unreported >= PROGRESS_INTERVAL || n == 0 {
+            self.reporter.progress(self.unreported);
+            self.unreported = 0;
+        }
+        Ok(n)
+    }
+}
+
+/// Extract the files in an archive, restoring permissions (without the bits
+/// in `umask`), symlinks, and modification times. The archive is verified
+/// first if it is retrieved. Archives with `delete_after_extract` are deleted
+/// afterwards, and archives with `partial` are read from the server.
+fn extract_archive(job: &ExtractJob, reporter: &ExtractReporter) -> errors::Result<()> {
+    let archive = &job.archive;
+    let info = &job.info;
+    let archive_path = Path::new(archive);
+    let remote = job.retrieve.as_ref().filter(|_| info.partial() && !archive_path.exists());
+    if let Some(retrieve_info) = remote {
+        if tempget::extract::is_extracted(archive, info) {
+            reporter.message(format!("{} has already been extracted, skipping", archive));
+            return Ok(());
+        }
+        return match extract_remote_zip(job, retrieve_info, reporter) {
+            Ok(()) => mark_extracted(archive, info),
+            Err(err) if retrieve_info.is_optional() => {
+                reporter.message(format!("Warning: could not extract optional archive {}: {}",
+                                         archive, err));
+                Ok(())
             },
-            ExtractInfo::Mapping(files) => {
-                for i in 0..zip_archive.len() {
-                    let f = zip_archive.by_index(i)?;
-                    if let Some(dest) = files.get(f.name()) {
-                        extract_files.push((i, Path::new(dest).to_owned()));
-                    }
-                }
+            Err(err) => Err(err)
+        };
+    }
+    if !archive_path.exists() && (info.stream() || info.delete_after_extract()) {
+        // The archive was extracted while it was downloaded, or it was
+        // deleted after being extracted by an earlier run.
+        return Ok(());
+    }
+    if let Some(retrieve_info) = &job.retrieve {
+        verify_file(archive, retrieve_info)?;
+    }
+    if tempget::tar::is_tar(archive) {
+        // The progress of tar archives is measured in bytes of the archive,
+        // since the sizes of the files are not known in advance.
+        let file = fs::File::open(archive_path)?;
+        reporter.start(Some(file.metadata()?.len()));
+        let limits = LimitTracker::new(archive, job.limits);
+        let mut extractor = tar_extractor(archive, info, job.umask, limits, false,
+                                          reporter.messages())?;
+        io::copy(&mut ProgressReader::new(file, reporter), &mut extractor)?;
+        finish_tar(extractor, job.umask, reporter)?;
+    } else {
+        let mut zip_archive = zip::read::ZipArchive::new(fs::File::open(archive_path)?)?;
+        let limits = LimitTracker::new(archive, job.limits);
+        extract_zip(&mut zip_archive, info, job.umask, limits, reporter)?;
+    }
+    if info.delete_after_extract() {
+        mark_extracted(archive, info)?;
+        fs::remove_file(archive_path)
+            .with_context(|_| format!("could not delete {}", archive))?;
+        reporter.message(format!("Deleted {}", archive));
+    }
+    Ok(())
+}
+
+/// Records that an archive which is not kept has been extracted, so that it
+/// is not downloaded again.
+fn mark_extracted(archive: &str, info: &ExtractInfo) -> errors::Result<()> {
+    tempget::extract::mark_extracted(archive, info)
+        .with_context(|_| format!("could not record that {} was extracted", archive))?;
+    Ok(())
+}
+
+/// Creates the directory that the archive is extracted into, so that it
+/// exists even if the archive is empty. The directories of files in a mapping
+/// are created as the files are extracted.
+fn create_extract_dir(info: &ExtractInfo) -> io::Result<()> {
+    match info.target() {
+        ExtractTarget::Directory(d) => fs::create_dir_all(d),
+        ExtractTarget::Mapping(_) => Ok(())
+    }
+}
+
+/// Returns a writer that extracts the tar archive written to it, which may be
+/// compressed. `report` is called with a message for each file. If `staged` is
+/// true, the files are only moved into place by `finish_tar`.
+fn tar_extractor(archive: &str, info: &ExtractInfo, umask: u32, limits: LimitTracker,
+                 staged: bool, report: Box<dyn FnMut(String) + Send>)
+                 -> errors::Result<DecompressWriter<TarParser<StreamExtractor>>> {
+    create_extract_dir(info)?;
+    let writer = if staged { EntryWriter::staged(umask) } else { EntryWriter::new(umask) };
+    let extractor = StreamExtractor::new(archive, writer, info, limits, report)?;
+    Ok(DecompressWriter::new_optional(TarParser::new(extractor)))
+}
+
+/// Finishes extracting a tar archive from a writer returned by
+/// `tar_extractor`, then extracts the archives nested inside of it.
+fn finish_tar(extractor: DecompressWriter<TarParser<StreamExtractor>>, umask: u32,
+              reporter: &ExtractReporter) -> errors::Result<()> {
+    let mut extractor = extractor.finish()?.finish()?;
+    extractor.commit()?;
+    extract_nested(extractor.into_nested(), umask, reporter)
+}
+
+/// Extracts archives that were stored while the archive containing them was
+/// extracted. They are counted against the limits of that archive.
+fn extract_nested(nested: Vec<NestedArchive>, umask: u32, reporter: &ExtractReporter)
+                  -> errors::Result<()> {
+    let reporter = reporter.nested();
+    for archive in nested {
+        reporter.message(format!("Extracting {}", archive.label));
+        let limits = archive.limits();
+        let res = (|| {
+            if tempget::tar::is_tar(&archive.name) {
+                let mut extractor = tar_extractor(&archive.label, &archive.info, umask, limits,
+                                                  false, reporter.messages())?;
+                io::copy(&mut archive.open()?, &mut extractor)?;
+                finish_tar(extractor, umask, &reporter)
+            } else {
+                let mut zip_archive = zip::read::ZipArchive::new(archive.open()?)?;
+                extract_zip(&mut zip_archive, &archive.info, umask, limits, &reporter)
             }
-        }
+        })();
+        res.with_context(|_| format!("could not extract {}", archive.label))?;
+    }
+    Ok(())
+}
 
-        for (index, dest_path) in extract_files {
-            let mut f = zip_archive.by_index(index)?;
-            if dest_path.exists() {
-                println!("{} already exists, skipping", dest_path.to_string_lossy());
+/// Extracts the files specified by `info` from a remote zip archive, reading
+/// only the parts of the archive that are needed. Each URL of the archive is
+/// tried in turn until one of them supports range requests.
+fn extract_remote_zip(job: &ExtractJob, retrieve_info: &template::RetrieveInfo,
+                      reporter: &ExtractReporter) -> errors::Result<()> {
+    let archive = &job.archive;
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(job.timeout))
+        .build()?;
+    let mut last_err = None;
+    for url in std::iter::once(retrieve_info.url()).chain(retrieve_info.mirrors()) {
+        let reader = match tempget::fetcher::RangeReader::new(client.clone(), url) {
+            Ok(reader) => reader,
+            Err(err) => {
+                last_err = Some(err.context(format!("could not read {}", url)).into());
                 continue;
             }
-            create_parent_dirs(&dest_path)?;
-            println!("Extracting {} to {}", f.name(), dest_path.to_string_lossy());
-            let mut dest_file = fs::File::create(&dest_path)?;
-            io::copy(&mut f, &mut dest_file)?;
+        };
+        reporter.message(format!("Reading {} from {}", archive, url));
+        let mut zip_archive = zip::read::ZipArchive::new(reader)?;
+        let limits = LimitTracker::new(archive, job.limits);
+        extract_zip(&mut zip_archive, &job.info, job.umask, limits, reporter)?;
+        let reader = zip_archive.into_inner();
+        reporter.message(format!("Downloaded {} of {} ({})",
+                                 ProgressState::display_bytes(reader.downloaded()), archive,
+                                 ProgressState::display_bytes(reader.size())));
+        return Ok(());
+    }
+    Err(last_err.expect("entry has no URLs"))
+}
+
+/// Extracts the files specified by `info` from a zip archive. The sizes in the
+/// archive are only used to report progress; the limits are checked by
+/// `tracker` against the data as it is decompressed.
+fn extract_zip<R: io::Read + io::Seek>(zip_archive: &mut zip::read::ZipArchive<R>,
+                                       info: &ExtractInfo, umask: u32, mut tracker: LimitTracker,
+                                       reporter: &ExtractReporter) -> errors::Result<()> {
+    let archive = tracker.archive().to_owned();
+    let extract_files = archive_entries(&archive, zip_archive, info)?;
+    create_extract_dir(info)?;
+    let mut writer = EntryWriter::new(umask);
+    let mut total = 0;
+    for (name, _, _) in &extract_files {
+        total += zip_archive.by_name(name)?.size();
+    }
+    reporter.start(Some(total));
+
+    for (name, dest, mode) in extract_files {
+        let dest_path = dest.joined();
+        let f = zip_archive.by_name(&name)?;
+        let meta = tempget::extract::EntryMeta::from_zip(&f);
+        let compressed = Some(f.compressed_size());
+        let contents = LimitedReader::new(f, &mut tracker, &name, compressed)?;
+        if writer.write(&dest, &meta, ProgressReader::new(contents, reporter))? {
+            reporter.message(format!("Extracting {} to {}", name, dest_path.to_string_lossy()));
+            if let (Some(mode), EntryKind::File) = (mode, meta.kind) {
+                tempget::extract::set_file_mode(&dest_path, mode)?;
+            }
+        } else {
+            reporter.message(format!("{} already exists, skipping",
+                                     dest_path.to_string_lossy()));
         }
     }
-    Ok(())
+
+    // Nested archives are stored in temporary files, then extracted once this
+    // archive has been.
+    let mut nested = Vec::new();
+    if let ExtractTarget::Mapping(files) = info.target() {
+        let mut nested_files: Vec<_> = files.iter()
+            .filter_map(|(name, dest)| dest.nested().map(|nested| (name, nested)))
+            .collect();
+        nested_files.sort_by(|a, b| a.0.cmp(b.0));
+        for (name, nested_info) in nested_files {
+            let f = match zip_archive.by_name(name) {
+                Ok(f) => f,
+                Err(zip::result::ZipError::FileNotFound) => continue,
+                Err(err) => return Err(err.into())
+            };
+            let compressed = Some(f.compressed_size());
+            let mut stored = NestedArchive::create(name, nested_info, &tracker)?;
+            io::copy(&mut LimitedReader::new(f, &mut tracker, name, compressed)?, &mut stored)?;
+            stored.finish()?;
+            nested.push(stored);
+        }
+    }
+    extract_nested(nested, umask, reporter)
+}
+
+/// Determines which files in the archive should be extracted. Returns the
+/// name of each file in the archive along with the path to extract it to and
+/// the permissions to set on it. It is an error for two files to be extracted
+/// to the same path.
+fn archive_entries<R: io::Read + io::Seek>(archive: &str,
+                                           zip_archive: &mut zip::read::ZipArchive<R>,
+                                           info: &ExtractInfo)
+                                           -> errors::Result<Vec<(String, EntryPath, Option<FileMode>)>> {
+    let mut extract_files = Vec::<(String, EntryPath, Option<FileMode>)>::new();
+    match info.target() {
+        ExtractTarget::Directory(d) => {
+            let dest_dir = Path::new(d);
+            let mut names = Vec::with_capacity(zip_archive.len());
+            for i in 0..zip_archive.len() {
+                names.push(zip_archive.by_index(i)?.name().to_owned());
+            }
+            let strip = tempget::extract::strip_count(info.strip_components(),
+                                                      names.iter().map(String::as_str));
+            for name in &names {
+                if name.ends_with('/') {
+                    // Don't add directories
+                    continue;
+                }
+                if let Some(stripped) = tempget::extract::strip_name(name, strip) {
+                    let dest = EntryPath { dir: dest_dir.to_owned(), path: stripped };
+                    extract_files.push((name.clone(), dest, None));
+                }
+            }
+        },
+        ExtractTarget::Mapping(files) if !files.keys().any(|k| tempget::extract::is_pattern(k)) => {
+            // Look up each file by name, so that the headers of the other
+            // files are not read. This matters for archives that are read
+            // from a server.
+            let mapping = tempget::extract::Mapping::new(files)?;
+            let mut names: Vec<_> = files.keys().collect();
+            names.sort();
+            for name in names {
+                match zip_archive.by_name(name) {
+                    Ok(_) => {},
+                    Err(zip::result::ZipError::FileNotFound) => continue,
+                    Err(err) => return Err(err.into())
+                }
+                for (path, dest) in mapping.destinations(name) {
+                    extract_files.push((name.clone(), path, dest.file_mode()));
+                }
+            }
+        },
+        ExtractTarget::Mapping(files) => {
+            let mapping = tempget::extract::Mapping::new(files)?;
+            for i in 0..zip_archive.len() {
+                let f = zip_archive.by_index(i)?;
+                for (path, dest) in mapping.destinations(f.name()) {
+                    extract_files.push((f.name().to_owned(), path, dest.file_mode()));
+                }
+            }
+        }
+    }
+    let mut dests = HashSet::new();
+    for (_, dest, _) in &extract_files {
+        let dest = dest.joined();
+        if !dests.insert(dest.clone()) {
+            return Err(errors::duplicate_destination(archive, &dest));
+        }
+    }
+    Ok(extract_files)
+}
+
+/// How the body of a response is written to disk.
+#[derive(Clone)]
+enum Output {
+    /// The body is written to the file as is.
+    File,
+    /// The body is a compressed file, which is decompressed as it is written.
+    Decompressed,
+    /// The body is a tar archive, which is extracted as it is downloaded
+    /// instead of being written to the file.
+    Extract(Arc<StreamOptions>)
+}
+
+/// How an archive is extracted while it is downloaded.
+struct StreamOptions {
+    info: ExtractInfo,
+    umask: u32,
+    limits: ExtractLimits,
+    /// The expected size and checksum of the archive, which can only be
+    /// verified after it has been extracted into the staging directory.
+    size: Option<u64>,
+    sha256: Option<String>
+}
+
+/// Returns a `Future` that represents sending the request and writing the
+/// contents of the response to the given file path.
+fn download(client: &req::Client,
+            request: req::Request,
+            path: &Path,
+            output: Output,
+            idx: usize,
+            prog_tx: SyncSender<DownloadStatus>,
+            timeout_dur: Duration)
+            -> impl Future<Item = (usize, SyncSender<DownloadStatus>), Error = errors::Error> {
+    let path = path.to_owned();
+    let timeout_secs = timeout_dur.as_secs();
+    client
+        .execute(request)
+        .timeout(timeout_dur)
+        .map_err(move |timer_err| {
+            let err_res: errors::Error =
+                if let Some(e) = timer_err.into_inner() {
+                    e.into()
+                } else {
+                    errors::timeout(timeout_secs)
+                };
+            err_res
+        })
+        .and_then(|response| {
+            let status = response.status();
+            if !status.is_success() {
+                Err(errors::status_code(status))
+            } else {
+                Ok(response)
+            }
+        })
+        .and_then(move |response| {
+            let size_opt = response.headers()
+                .get(reqwest::header::CONTENT_LENGTH)
+                .and_then(|ct_len| ct_len.to_str().ok())
+                .and_then(|ct_len| ct_len.parse().ok());
+
+            prog_tx.send(DownloadStatus::Start(idx, size_opt)).unwrap();
+
+            write_file(&path, response, output, idx, prog_tx, timeout_dur.clone())
+        })
 }
 
 /// Returns a `Future` that represents asynchronously writing the contents of
-/// the `Response` to the given file path. The resulting value is the given file
-/// path.
+/// the `Response` to the given file path. The resulting value is the index of
+/// the file along with the progress channel.
 fn write_file(file_path: &Path,
               response: req::Response,
+              output: Output,
               idx: usize,
               prog_tx: SyncSender<DownloadStatus>,
               timeout: Duration)
-              -> impl Future<Item = (usize, SyncSender<DownloadStatus>), Error = errors::Error> {
-    futures::future::result(create_parent_dirs(&file_path).map(|_| file_path.to_owned()))
+              -> Box<dyn Future<Item = (usize, SyncSender<DownloadStatus>), Error = errors::Error> + Send> {
+    let prog_tx_prog = prog_tx.clone();
+    let body = response.into_body()
         .from_err::<errors::Error>()
-        .and_then(|path| tokio::fs::File::create(path).from_err::<_>())
-        .and_then(move |file| {
-            let codec = tokio::codec::BytesCodec::new();
-            let file_sink = tokio::codec::FramedWrite::new(file, codec);
-            let prog_tx_prog = prog_tx.clone();
-            response.into_body()
-                .from_err::<_>()
-                .inspect(move |chunk| {
-                    prog_tx_prog.send(DownloadStatus::Progress(
-                        idx, chunk.len(), Instant::now())).unwrap();
+        .inspect(move |chunk| {
+            prog_tx_prog.send(DownloadStatus::Progress(
+                idx, chunk.len(), Instant::now())).unwrap();
+        })
+        .timeout(timeout)
+        .map_err(move |timer_err| timer_err.into_inner().unwrap_or(
+            errors::timeout(timeout.as_secs())));
+    let created = create_parent_dirs(&file_path).map(|_| file_path.to_owned());
+    let written: Box<dyn Future<Item = (), Error = errors::Error> + Send> = match output {
+        Output::File => Box::new(futures::future::result(created)
+            .from_err::<errors::Error>()
+            .and_then(|path| tokio::fs::File::create(path).from_err::<_>())
+            .and_then(move |file| {
+                let codec = tokio::codec::BytesCodec::new();
+                let file_sink = tokio::codec::FramedWrite::new(file, codec);
+                body.map(|chunk| (&*chunk).into())
+                    .forward(file_sink)
+                    .map(|_| ())
+            })),
+        Output::Decompressed => {
+            let path = file_path.to_owned();
+            Box::new(futures::future::result(created.and_then(fs::File::create))
+                .from_err::<errors::Error>()
+                .and_then(move |file| {
+                    // The decoders write to the file synchronously.
+                    let writer = tempget::decompress::DecompressWriter::new(file);
+                    body.fold(writer, |mut writer, chunk| {
+                        io::Write::write_all(&mut writer, &chunk).map(|_| writer)
+                            .map_err(errors::Error::from)
+                    })
                 })
-                .map(|chunk| (&*chunk).into())
-                .timeout(timeout)
-                .map_err(move |timer_err| timer_err.into_inner().unwrap_or(
-                    errors::timeout(timeout.as_secs())))
-                .forward(file_sink)
-                .map(move |_| {
-                    prog_tx.send(DownloadStatus::Finish(idx)).unwrap();
-                    (idx, prog_tx)
+                .and_then(|writer| {
+                    writer.finish()?;
+                    Ok(())
                 })
-        })
+                .map_err(move |err| {
+                    // A file that was not fully decompressed would otherwise
+                    // be skipped as existing by the next run.
+                    let _ = fs::remove_file(&path);
+                    err
+                }))
+        },
+        Output::Extract(stream) => {
+            let archive = file_path.to_string_lossy().into_owned();
+            // The progress of the download is shown instead.
+            let reporter = ExtractReporter { id: idx, tx: prog_tx.clone(), progress: false };
+            // The files are staged until the archive has been verified, so
+            // that nothing is left behind if it does not match.
+            let limits = LimitTracker::new(&archive, stream.limits);
+            let extractor = tar_extractor(&archive, &stream.info, stream.umask, limits,
+                                          true, reporter.messages())
+                .map(tempget::checksum::Sha256Writer::new)
+                .map_err(extraction_error);
+            Box::new(futures::future::result(extractor)
+                .and_then(move |writer| {
+                    // The entries are extracted synchronously.
+                    body.fold(writer, |mut writer, chunk| {
+                        io::Write::write_all(&mut writer, &chunk).map(|_| writer)
+                            .map_err(extraction_error)
+                    })
+                })
+                .and_then(move |writer| {
+                    let (extractor, size, sha256) = writer.finish();
+                    if let Some(expected) = stream.size.filter(|&expected| expected != size) {
+                        return Err(errors::size_mismatch(&archive, expected, size));
+                    }
+                    if let Some(expected) = &stream.sha256 {
+                        if !sha256.eq_ignore_ascii_case(expected) {
+                            return Err(errors::checksum_mismatch(&archive, expected, &sha256));
+                        }
+                    }
+                    finish_tar(extractor, stream.umask, &reporter)
+                        .and_then(|_| mark_extracted(&archive, &stream.info))
+                        .map_err(extraction_error)
+                }))
+        }
+    };
+    Box::new(written.map(move |_| {
+        prog_tx.send(DownloadStatus::Finish(idx)).unwrap();
+        (idx, prog_tx)
+    }))
+}
+
+/// Gives an error that occurred while extracting an archive as it was
+/// downloaded the kind of an extraction error, since it is reported as a
+/// failed download.
+fn extraction_error<E: Into<errors::Error>>(err: E) -> errors::Error {
+    err.into().context(ErrorKind::Extraction).into()
 }
 
 /// Create all parent directories of the given path.
@@ -0,0 +1,70 @@
+use std::env;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Describes the operating system and architecture that files are retrieved
+/// for.
+pub struct Platform {
+    /// The operating system, using the same names as `std::env::consts::OS`
+    /// (e.g. `linux`, `macos`, `windows`).
+    pub os: String,
+    /// The architecture, using the same names as `std::env::consts::ARCH`
+    /// (e.g. `x86_64`, `x86`, `aarch64`).
+    pub arch: String,
+    /// The target triple (e.g. `x86_64-unknown-linux-gnu`).
+    pub target: String
+}
+
+/// Operating systems that can appear in a target triple, along with their
+/// names in `std::env::consts::OS`. Android comes before Linux since Android
+/// triples look like `aarch64-linux-android`.
+const TARGET_OSES: &[(&str, &str)] = &[
+    ("android", "android"),
+    ("linux", "linux"),
+    ("ios", "ios"),
+    ("darwin", "macos"),
+    ("windows", "windows"),
+    ("freebsd", "freebsd"),
+    ("netbsd", "netbsd"),
+    ("openbsd", "openbsd"),
+    ("dragonfly", "dragonfly"),
+    ("solaris", "solaris"),
+    ("illumos", "illumos")
+];
+
+impl Platform {
+    /// Returns the platform that this program is running on.
+    pub fn host() -> Self {
+        Platform {
+            os: env::consts::OS.to_owned(),
+            arch: env::consts::ARCH.to_owned(),
+            target: env!("TEMPGET_TARGET").to_owned()
+        }
+    }
+
+    /// Determines the platform described by the given target triple.
+    pub fn from_target(target: &str) -> Self {
+        let mut parts = target.split('-');
+        let arch = match parts.next().unwrap_or_default() {
+            "i386" | "i586" | "i686" => "x86",
+            a if a.starts_with("arm") || a.starts_with("thumb") => "arm",
+            a => a
+        };
+        let parts: Vec<&str> = parts.collect();
+        let os = TARGET_OSES.iter()
+            .find(|(name, _)| parts.contains(name))
+            .map(|(_, os)| *os)
+            .or_else(|| parts.get(1).cloned())
+            .unwrap_or("unknown");
+        Platform {
+            os: os.to_owned(),
+            arch: arch.to_owned(),
+            target: target.to_owned()
+        }
+    }
+}
+
+impl Default for Platform {
+    fn default() -> Self {
+        Self::host()
+    }
+}
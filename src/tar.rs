@@ -0,0 +1,531 @@
+use std::io::{self, Write};
+
+/// The size of a header or data block in a tar archive.
+const BLOCK_SIZE: usize = 512;
+
+/// The file extensions of tar archives, with and without compression.
+const TAR_EXTENSIONS: [&str; 10] = [
+    ".tar", ".tar.gz", ".tgz", ".tar.bz2", ".tbz2", ".tbz", ".tar.xz", ".txz", ".tar.zst", ".tzst"
+];
+
+/// Returns true if the path has the file extension of a tar archive, which may
+/// be compressed.
+pub fn is_tar(path: &str) -> bool {
+    TAR_EXTENSIONS.iter().any(|ext| path.ends_with(ext))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// The kinds of entries in a tar archive.
+pub enum TarKind {
+    File,
+    Directory,
+    /// A symlink to the given target.
+    Symlink(String),
+    /// Any other kind of entry, such as a hard link or a device, which is not
+    /// extracted.
+    Other
+}
+
+#[derive(Debug, Clone)]
+/// An entry in a tar archive.
+pub struct TarEntry {
+    pub name: String,
+    pub kind: TarKind,
+    /// The Unix permission bits of the entry.
+    pub mode: u32,
+    /// The modification time of the entry, in seconds since the Unix epoch.
+    pub mtime: u64,
+    /// The size of the contents of the entry in bytes.
+    pub size: u64
+}
+
+/// Receives the entries of a tar archive as they are parsed.
+pub trait TarHandler {
+    /// Called at the start of each entry.
+    fn start(&mut self, entry: &TarEntry) -> io::Result<()>;
+    /// Called with each part of the contents of the current entry.
+    fn data(&mut self, buf: &[u8]) -> io::Result<()>;
+    /// Called once all of the contents of the current entry have been given.
+    fn end(&mut self) -> io::Result<()>;
+}
+
+/// What the parser expects next.
+enum State {
+    /// The header of the next entry.
+    Header,
+    /// The contents of the current entry.
+    Data { remaining: u64, padding: usize },
+    /// The contents of a GNU long name or pax header, which describe the
+    /// next entry.
+    Extension { kind: u8, remaining: usize, padding: usize },
+    /// Padding after the contents of an entry.
+    Padding(usize),
+    /// The end of the archive, after which everything is ignored.
+    End
+}
+
+/// A tar parser that is given the archive by writing to it, so that archives
+/// can be extracted while they are downloaded. Supports ustar, GNU, and pax
+/// archives.
+pub struct TarParser<H> {
+    handler: H,
+    state: State,
+    /// The part of the current header or extension that has been read.
+    buf: Vec<u8>,
+    /// The name given by a GNU long name or pax header for the next entry.
+    long_name: Option<String>,
+    /// The symlink target given by a GNU long link or pax header for the next
+    /// entry.
+    long_link: Option<String>,
+    /// The size given by a pax header for the next entry.
+    pax_size: Option<u64>
+}
+
+impl<H: TarHandler> TarParser<H> {
+    pub fn new(handler: H) -> Self {
+        TarParser {
+            handler,
+            state: State::Header,
+            buf: Vec::with_capacity(BLOCK_SIZE),
+            long_name: None,
+            long_link: None,
+            pax_size: None
+        }
+    }
+
+    /// Checks that the whole archive has been read, and returns the handler.
+    pub fn finish(self) -> io::Result<H> {
+        match self.state {
+            State::End | State::Header if self.buf.is_empty() => Ok(self.handler),
+            _ => Err(invalid("the tar archive ended unexpectedly"))
+        }
+    }
+
+    /// Parses a complete header.
+    fn header(&mut self) -> io::Result<()> {
+        let header = std::mem::replace(&mut self.buf, Vec::with_capacity(BLOCK_SIZE));
+        if header.iter().all(|&b| b == 0) {
+            self.state = State::End;
+            return Ok(());
+        }
+        if parse_number(&header[148..156])? != checksum(&header) {
+            return Err(invalid("invalid tar header checksum"));
+        }
+        let size = match self.pax_size.take() {
+            Some(size) => size,
+            None => parse_number(&header[124..136])?
+        };
+        let kind = header[156];
+        if kind == b'L' || kind == b'K' || kind == b'x' || kind == b'g' {
+            if size > 1 << 20 {
+                return Err(invalid("tar extension header is too large"));
+            }
+            self.state = State::Extension {
+                kind,
+                remaining: size as usize,
+                padding: padding(size)
+            };
+            return Ok(());
+        }
+
+        let name = match self.long_name.take() {
+            Some(name) => name,
+            None => {
+                let name = field_str(&header[0..100]);
+                // Only POSIX ustar archives split long names into a prefix.
+                let prefix = if &header[257..263] == b"ustar\0" {
+                    field_str(&header[345..500])
+                } else {
+                    String::new()
+                };
+                if prefix.is_empty() { name } else { format!("{}/{}", prefix, name) }
+            }
+        };
+        let link = self.long_link.take().unwrap_or_else(|| field_str(&header[157..257]));
+        let kind = match kind {
+            b'0' | b'\0' | b'7' => TarKind::File,
+            b'5' => TarKind::Directory,
+            b'2' => TarKind::Symlink(link),
+            _ => TarKind::Other
+        };
+        let entry = TarEntry {
+            name,
+            kind,
+            mode: parse_number(&header[100..108])? as u32 & 0o7777,
+            mtime: parse_number(&header[136..148])?,
+            size
+        };
+        self.handler.start(&entry)?;
+        if size == 0 {
+            self.handler.end()?;
+            self.state = State::Header;
+        } else {
+            self.state = State::Data { remaining: size, padding: padding(size) };
+        }
+        Ok(())
+    }
+
+    /// Applies a complete GNU long name or pax header to the next entry.
+    fn extension(&mut self, kind: u8) -> io::Result<()> {
+        let data = std::mem::take(&mut self.buf);
+        match kind {
+            b'L' => self.long_name = Some(field_str(&data)),
+            b'K' => self.long_link = Some(field_str(&data)),
+            b'x' => {
+                for (key, value) in parse_pax(&data)? {
+                    match key.as_str() {
+                        "path" => self.long_name = Some(value),
+                        "linkpath" => self.long_link = Some(value),
+                        "size" => {
+                            self.pax_size = Some(value.parse()
+                                .map_err(|_| invalid("invalid size in pax header"))?);
+                        },
+                        _ => {}
+                    }
+                }
+            },
+            // Global pax headers do not contain anything that is used.
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl<H: TarHandler> Write for TarParser<H> {
+    fn write(&mut self, mut buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len();
+        while !buf.is_empty() {
+            match self.state {
+                State::Header => {
+                    let n = (BLOCK_SIZE - self.buf.len()).min(buf.len());
+                    self.buf.extend_from_slice(&buf[..n]);
+                    buf = &buf[n..];
+                    if self.buf.len() == BLOCK_SIZE {
+                        self.header()?;
+                    }
+                },
+                State::Data { remaining, padding } => {
+                    let n = remaining.min(buf.len() as u64) as usize;
+                    self.handler.data(&buf[..n])?;
+                    buf = &buf[n..];
+                    if remaining == n as u64 {
+                        self.handler.end()?;
+                        self.state = State::Padding(padding);
+                    } else {
+                        self.state = State::Data { remaining: remaining - n as u64, padding };
+                    }
+                },
+                State::Extension { kind, remaining, padding } => {
+                    let n = remaining.min(buf.len());
+                    self.buf.extend_from_slice(&buf[..n]);
+                    buf = &buf[n..];
+                    if remaining == n {
+                        self.extension(kind)?;
+                        self.state = State::Padding(padding);
+                    } else {
+                        self.state = State::Extension { kind, remaining: remaining - n, padding };
+                    }
+                },
+                State::Padding(remaining) => {
+                    let n = remaining.min(buf.len());
+                    buf = &buf[n..];
+                    self.state = if remaining == n {
+                        State::Header
+                    } else {
+                        State::Padding(remaining - n)
+                    };
+                },
+                State::End => break
+            }
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Returns the number of padding bytes after contents of the given size.
+fn padding(size: u64) -> usize {
+    let rem = (size % BLOCK_SIZE as u64) as usize;
+    if rem == 0 { 0 } else { BLOCK_SIZE - rem }
+}
+
+/// Computes the checksum of a header, in which the checksum field counts as
+/// spaces.
+fn checksum(header: &[u8]) -> u64 {
+    header.iter()
+        .enumerate()
+        .map(|(i, &b)| if (148..156).contains(&i) { u64::from(b' ') } else { u64::from(b) })
+        .sum()
+}
+
+/// Reads a string field, which ends at the first NUL byte.
+fn field_str(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// Parses a numeric field, which is either octal text or a big-endian binary
+/// number (for large values in GNU archives).
+fn parse_number(field: &[u8]) -> io::Result<u64> {
+    if field[0] & 0x80 != 0 {
+        let mut value: u64 = u64::from(field[0] & 0x7f);
+        for &b in &field[1..] {
+            value = value.checked_mul(256)
+                .ok_or_else(|| invalid("number in tar header is too large"))?
+                + u64::from(b);
+        }
+        return Ok(value);
+    }
+    let text = field_str(field);
+    let text = text.trim_matches(|c| c == ' ' || c == '\0');
+    if text.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(text, 8).map_err(|_| invalid("invalid number in tar header"))
+}
+
+/// Parses the records of a pax header, which have the form
+/// `<length> <key>=<value>\n`.
+fn parse_pax(mut data: &[u8]) -> io::Result<Vec<(String, String)>> {
+    let mut records = Vec::new();
+    while !data.is_empty() {
+        let space = data.iter().position(|&b| b == b' ')
+            .ok_or_else(|| invalid("invalid pax header"))?;
+        let len: usize = std::str::from_utf8(&data[..space]).ok()
+            .and_then(|len| len.parse().ok())
+            .filter(|&len| len > space + 1 && len <= data.len())
+            .ok_or_else(|| invalid("invalid pax header"))?;
+        let record = String::from_utf8_lossy(&data[space + 1..len - 1]).into_owned();
+        if let Some(eq) = record.find('=') {
+            records.push((record[..eq].to_owned(), record[eq + 1..].to_owned()));
+        }
+        data = &data[len..];
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Builds a ustar header for an entry of the given kind and size.
+    pub(crate) fn header(name: &str, kind: u8, size: u64) -> Vec<u8> {
+        let mut header = vec![0; BLOCK_SIZE];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..107].copy_from_slice(b"0000644");
+        header[124..135].copy_from_slice(format!("{:011o}", size).as_bytes());
+        header[136..147].copy_from_slice(b"00000000000");
+        header[156] = kind;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        seal(&mut header);
+        header
+    }
+
+    /// Updates the checksum of a header after it has been changed.
+    pub(crate) fn seal(header: &mut [u8]) {
+        let sum = checksum(header);
+        header[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+        header[155] = b' ';
+    }
+
+    /// Appends the contents of an entry to its header, padded to a whole block.
+    pub(crate) fn entry(mut header: Vec<u8>, contents: &[u8]) -> Vec<u8> {
+        header.extend_from_slice(contents);
+        header.resize(header.len() + padding(contents.len() as u64), 0);
+        header
+    }
+
+    pub(crate) fn file(name: &str, contents: &[u8]) -> Vec<u8> {
+        entry(header(name, b'0', contents.len() as u64), contents)
+    }
+
+    pub(crate) fn symlink(name: &str, target: &str) -> Vec<u8> {
+        let mut header = header(name, b'2', 0);
+        header[157..157 + target.len()].copy_from_slice(target.as_bytes());
+        seal(&mut header);
+        header
+    }
+
+    /// Joins the entries into an archive, followed by the two empty blocks
+    /// that end it.
+    pub(crate) fn archive(entries: &[Vec<u8>]) -> Vec<u8> {
+        let mut archive = entries.concat();
+        archive.resize(archive.len() + 2 * BLOCK_SIZE, 0);
+        archive
+    }
+
+    /// Returns a pax header record, which starts with its own length.
+    fn pax_record(key: &str, value: &str) -> String {
+        let rest = format!(" {}={}\n", key, value);
+        let mut len = rest.len() + 1;
+        while (len.to_string().len() + rest.len()) != len {
+            len += 1;
+        }
+        format!("{}{}", len, rest)
+    }
+
+    #[derive(Default)]
+    /// Records the entries given to it along with their contents.
+    struct Recorder {
+        entries: Vec<(TarEntry, Vec<u8>)>,
+        open: bool
+    }
+
+    impl TarHandler for Recorder {
+        fn start(&mut self, entry: &TarEntry) -> io::Result<()> {
+            assert!(!self.open);
+            self.entries.push((entry.clone(), Vec::new()));
+            self.open = true;
+            Ok(())
+        }
+
+        fn data(&mut self, buf: &[u8]) -> io::Result<()> {
+            assert!(self.open);
+            self.entries.last_mut().unwrap().1.extend_from_slice(buf);
+            Ok(())
+        }
+
+        fn end(&mut self) -> io::Result<()> {
+            assert!(self.open);
+            self.open = false;
+            Ok(())
+        }
+    }
+
+    /// Parses an archive that is written in chunks of the given size.
+    fn parse(archive: &[u8], chunk_size: usize) -> io::Result<Vec<(TarEntry, Vec<u8>)>> {
+        let mut parser = TarParser::new(Recorder::default());
+        for chunk in archive.chunks(chunk_size) {
+            parser.write_all(chunk)?;
+        }
+        parser.finish().map(|recorder| recorder.entries)
+    }
+
+    fn names(entries: &[(TarEntry, Vec<u8>)]) -> Vec<&str> {
+        entries.iter().map(|(entry, _)| entry.name.as_str()).collect()
+    }
+
+    #[test]
+    fn ustar() {
+        let mut prefixed = header("file.txt", b'0', 3);
+        prefixed[345..356].copy_from_slice(b"some/prefix");
+        seal(&mut prefixed);
+        let archive = archive(&[
+            header("dir/", b'5', 0),
+            file("dir/hello.txt", b"hello"),
+            symlink("dir/link", "hello.txt"),
+            entry(prefixed, b"abc"),
+            header("dir/device", b'3', 0)
+        ]);
+        let entries = parse(&archive, archive.len()).unwrap();
+        assert_eq!(names(&entries),
+                   ["dir/", "dir/hello.txt", "dir/link", "some/prefix/file.txt", "dir/device"]);
+        assert_eq!(entries[0].0.kind, TarKind::Directory);
+        assert_eq!(entries[1].0.kind, TarKind::File);
+        assert_eq!(entries[1].0.mode, 0o644);
+        assert_eq!(entries[1].0.size, 5);
+        assert_eq!(entries[1].1, b"hello");
+        assert_eq!(entries[2].0.kind, TarKind::Symlink("hello.txt".to_owned()));
+        assert_eq!(entries[3].1, b"abc");
+        assert_eq!(entries[4].0.kind, TarKind::Other);
+    }
+
+    #[test]
+    fn gnu_long_names() {
+        let name = format!("{}/file.txt", "long".repeat(40));
+        let target = format!("{}/target", "long".repeat(40));
+        let mut link = symlink("short", "short");
+        link.resize(BLOCK_SIZE, 0);
+        let archive = archive(&[
+            entry(header("././@LongLink", b'L', name.len() as u64 + 1), format!("{}\0", name).as_bytes()),
+            file(&name[..99], b"contents"),
+            entry(header("././@LongLink", b'K', target.len() as u64), target.as_bytes()),
+            link,
+            file("after.txt", b"")
+        ]);
+        let entries = parse(&archive, archive.len()).unwrap();
+        assert_eq!(names(&entries), [name.as_str(), "short", "after.txt"]);
+        assert_eq!(entries[0].1, b"contents");
+        assert_eq!(entries[1].0.kind, TarKind::Symlink(target));
+    }
+
+    #[test]
+    fn pax_path_and_size() {
+        let name = format!("{}/file.txt", "pax".repeat(50));
+        let contents = vec![b'x'; 700];
+        let records = format!("{}{}{}", pax_record("path", &name), pax_record("size", "700"),
+                              pax_record("mtime", "1.5"));
+        // The size field of the entry is ignored, since the pax header gives it.
+        let mut file_header = header("truncated", b'0', 1);
+        file_header.extend_from_slice(&contents);
+        file_header.resize(file_header.len() + padding(700), 0);
+        let archive = archive(&[
+            entry(header("pax_global_header", b'g', 0), b""),
+            entry(header("PaxHeaders/file", b'x', records.len() as u64), records.as_bytes()),
+            file_header,
+            file("next.txt", b"next")
+        ]);
+        let entries = parse(&archive, archive.len()).unwrap();
+        assert_eq!(names(&entries), [name.as_str(), "next.txt"]);
+        assert_eq!(entries[0].0.size, 700);
+        assert_eq!(entries[0].1, contents);
+        assert_eq!(entries[1].1, b"next");
+    }
+
+    #[test]
+    fn chunked_writes() {
+        let long_name = "chunked/".repeat(20);
+        let archive = archive(&[
+            entry(header("././@LongLink", b'L', long_name.len() as u64), long_name.as_bytes()),
+            file("ignored", &[1; 600]),
+            file("a.txt", b"a"),
+            file("b.bin", &[2; 1024]),
+            symlink("c", "a.txt")
+        ]);
+        let expected = parse(&archive, archive.len()).unwrap();
+        for &chunk_size in &[1, 7, 100, 511, 512, 513, 1000] {
+            let entries = parse(&archive, chunk_size).unwrap();
+            assert_eq!(names(&entries), names(&expected));
+            for ((entry, contents), (expected_entry, expected_contents)) in entries.iter().zip(&expected) {
+                assert_eq!(entry.kind, expected_entry.kind);
+                assert_eq!(contents, expected_contents);
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_archives() {
+        let archive = archive(&[file("a.txt", &[1; 600]), file("b.txt", b"b")]);
+        // Cut off inside of a header, the contents, and the padding.
+        for &len in &[100, 512 + 300, 512 + 700] {
+            assert!(parse(&archive[..len], 64).is_err(), "{} bytes", len);
+        }
+        // An archive without the empty blocks at the end is still complete.
+        assert_eq!(parse(&archive[..2560], 64).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn invalid_checksum() {
+        let mut archive = archive(&[file("a.txt", b"a")]);
+        archive[0] = b'b';
+        let err = parse(&archive, archive.len()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(parse_number(b"0000644\0").unwrap(), 0o644);
+        assert_eq!(parse_number(b"  755 \0").unwrap(), 0o755);
+        assert_eq!(parse_number(b"\0\0\0\0").unwrap(), 0);
+        assert_eq!(parse_number(&[0x80, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0]).unwrap(), 1 << 32);
+        assert!(parse_number(b"0009").is_err());
+    }
+}
@@ -1,42 +1,1242 @@
+use std::ffi::OsStr;
+use std::fmt;
 use std::fs;
-use std::path::Path;
-use std::io::Read;
-use std::collections::HashMap;
-use serde_derive::Deserialize;
-use url_serde; // For deriving Deserialize for Url
+use std::path::{Path, PathBuf};
+use std::io::{self, Read};
+use std::str::FromStr;
+use std::collections::{HashMap, HashSet};
+use failure::ResultExt;
+use reqwest::Url;
+use serde::de::{self, Deserialize, Deserializer, MapAccess, Visitor};
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use url_serde; // For deriving Deserialize and Serialize for Url
 
 use crate::errors;
+use crate::platform::Platform;
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 /// Represents a template file.
 pub struct Template {
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    /// The variables that were substituted into the template. The built-in
+    /// `os`, `arch`, and `target` variables are not included unless a
+    /// template or `--var` defines them, so that they are not fixed when the
+    /// template is written out.
+    pub vars: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
     /// The files to download from the given URLs.
-    pub retrieve: HashMap<String, url_serde::SerdeUrl>,
-    #[serde(default)]
+    pub retrieve: HashMap<String, RetrieveInfo>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
     /// The file archives that should be extracted.
     pub extract: HashMap<String, ExtractInfo>
 }
 
+#[derive(Debug, Clone, Default)]
+/// Builds a `Template` in code. Entries with the same path or archive replace
+/// earlier entries.
+pub struct TemplateBuilder {
+    templ: Template
+}
+
+impl TemplateBuilder {
+    /// Defines a variable.
+    pub fn var<N: Into<String>, V: Into<String>>(mut self, name: N, value: V) -> Self {
+        self.templ.vars.insert(name.into(), value.into());
+        self
+    }
+
+    /// Downloads the file at the given URL to the given path.
+    pub fn retrieve<P: Into<String>>(self, path: P, url: Url) -> Self {
+        self.retrieve_info(path, RetrieveInfo::Url(url_serde::Serde(url)))
+    }
+
+    /// Downloads a file to the given path using the given options.
+    pub fn retrieve_info<P: Into<String>>(mut self, path: P, info: RetrieveInfo) -> Self {
+        self.templ.retrieve.insert(path.into(), info);
+        self
+    }
+
+    /// Extracts the given archive.
+    pub fn extract<A: Into<String>>(mut self, archive: A, info: ExtractInfo) -> Self {
+        self.templ.extract.insert(archive.into(), info);
+        self
+    }
+
+    /// Returns the template.
+    pub fn build(self) -> Template {
+        self.templ
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+/// Options that control how a template is loaded.
+pub struct LoadOptions {
+    /// Variables that override the variables defined in the template.
+    pub vars: HashMap<String, String>,
+    /// The platform that files are retrieved for. Entries with conditions
+    /// that do not match this platform are removed from the template.
+    pub platform: Platform,
+    /// The format of the template. When not given, the format is determined
+    /// from the file extension. Included templates always use their file
+    /// extension.
+    pub format: Option<Format>,
+    /// The expected SHA-256 checksum of the template, as a hexadecimal
+    /// string. Templates that it includes are not verified.
+    pub sha256: Option<String>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The formats that a template can be written in.
+pub enum Format {
+    Toml,
+    Json,
+    Yaml
+}
+
+impl Format {
+    /// Determines the format of a template from its file extension. Returns
+    /// `None` if the extension is not recognized.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_lowercase();
+        match ext.as_str() {
+            "toml" => Some(Format::Toml),
+            "json" => Some(Format::Json),
+            "yaml" | "yml" => Some(Format::Yaml),
+            _ => None
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "toml" => Ok(Format::Toml),
+            "json" => Ok(Format::Json),
+            "yaml" => Ok(Format::Yaml),
+            _ => Err(format!("unknown template format: {}", s))
+        }
+    }
+}
+
 impl Template {
+    /// Returns a builder for a template with no entries.
+    pub fn builder() -> TemplateBuilder {
+        TemplateBuilder::default()
+    }
+
+    /// Serializes the template as TOML. Entries are sorted by path.
+    ///
+    /// A template returned by `load` is a resolved snapshot for one platform:
+    /// its variables (including `os`, `arch`, and `target`) are already
+    /// substituted, entries whose `when` condition does not match the
+    /// platform have been removed, and the entries of included templates are
+    /// written as if they were part of the template.
+    pub fn to_toml(&self) -> errors::Result<String> {
+        // Converting to a `toml::Value` first ensures that tables are written
+        // after plain values, as TOML requires.
+        let value = toml::Value::try_from(self)?;
+        Ok(toml::to_string(&value)?)
+    }
+
+    /// Serializes the template as pretty-printed JSON. Entries are sorted by
+    /// path.
+    pub fn to_json(&self) -> errors::Result<String> {
+        let value = serde_json::to_value(self)?;
+        Ok(serde_json::to_string_pretty(&value)?)
+    }
+
     pub fn from_file<P: AsRef<Path>>(file_path: P) -> errors::Result<Self> {
-        let mut cfg = config::Config::new();
-        let mut file = fs::File::open(file_path)?;
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
-        let cfg_file = config::File::from_str(&contents, config::FileFormat::Toml);
-        cfg.merge(cfg_file)?;
-        let res = cfg.try_into()?;
+        let source = TemplateSource::File(file_path.as_ref().to_owned());
+        Self::load(&source, &LoadOptions::default())
+    }
+
+    /// Loads the template from the given source, along with the templates that
+    /// it includes. Variables are substituted into the `retrieve` and
+    /// `extract` sections, and entries that are not meant for the platform
+    /// given in the options are removed.
+    pub fn load(source: &TemplateSource, options: &LoadOptions) -> errors::Result<Self> {
+        let platform = &options.platform;
+        let mut builtin_vars = HashMap::new();
+        builtin_vars.insert("os".to_owned(), platform.os.clone());
+        builtin_vars.insert("arch".to_owned(), platform.arch.clone());
+        builtin_vars.insert("target".to_owned(), platform.target.clone());
+        let cli_vars: HashMap<String, String> = options.vars.iter()
+            .map(|(name, value)| (name.to_lowercase(), value.clone()))
+            .collect();
+
+        // Includes may only use built-in and command line variables, since the
+        // variables in the included templates are not known yet.
+        let mut include_vars = builtin_vars.clone();
+        include_vars.extend(cli_vars.clone());
+        let mut reader = IncludeReader::new(&include_vars, options.sha256.is_some());
+        reader.read(source, options.format, options.sha256.as_deref())?;
+        let mut files = reader.files;
+
+        // Variables in a template override the variables in the templates that
+        // it includes, since included templates are read first.
+        let mut vars = builtin_vars;
+        let mut defined: HashSet<String> = cli_vars.keys().cloned().collect();
+        for (_, table) in &mut files {
+            if let Some(value) = table.remove("vars") {
+                for (name, value) in serde_json::from_value::<HashMap<String, JsonValue>>(value)? {
+                    let value = match value {
+                        JsonValue::String(s) => s,
+                        other => other.to_string()
+                    };
+                    defined.insert(name.to_lowercase());
+                    vars.insert(name.to_lowercase(), value);
+                }
+            }
+        }
+        vars.extend(cli_vars);
+        let vars = crate::vars::resolve(&vars)?;
+
+        let mut res = Template {
+            vars: HashMap::new(),
+            retrieve: HashMap::new(),
+            extract: HashMap::new()
+        };
+        // Entries that are not meant for the platform are checked, but do not
+        // conflict with other entries.
+        let matches = |when: Option<&Condition>| when.map(|c| c.matches(platform)).unwrap_or(true);
+        let mut retrieve_sources = HashMap::<String, String>::new();
+        let mut extract_sources = HashMap::<String, String>::new();
+        for (path, mut table) in files {
+            for section in &["retrieve", "extract"] {
+                if let Some(value) = table.get_mut(*section) {
+                    interpolate_value(value, &vars)?;
+                }
+            }
+            let templ: Template = serde_json::from_value(JsonValue::Object(table))
+                .with_context(|_| format!("could not load {}", path))?;
+            let mut retrieve: Vec<_> = templ.retrieve.into_iter().collect();
+            retrieve.sort_by(|a, b| a.0.cmp(&b.0));
+            for (file, info) in retrieve {
+                if let RetrieveInfo::Detailed(opts) = &info {
+                    if opts.mode.is_some() && opts.executable {
+                        return Err(errors::invalid_entry("retrieve", &file, MODE_CONFLICT));
+                    }
+                }
+                if !matches(info.when()) {
+                    continue;
+                }
+                if let Some(other) = retrieve_sources.insert(file.clone(), path.clone()) {
+                    return Err(errors::conflicting_entry("retrieve", &file, &other, &path));
+                }
+                res.retrieve.insert(file, info);
+            }
+            let mut extract: Vec<_> = templ.extract.into_iter().collect();
+            extract.sort_by(|a, b| a.0.cmp(&b.0));
+            for (archive, info) in extract {
+                validate_extract(&archive, &info, false)?;
+                if !matches(info.when()) {
+                    continue;
+                }
+                if let Some(other) = extract_sources.insert(archive.clone(), path.clone()) {
+                    return Err(errors::conflicting_entry("extract", &archive, &other, &path));
+                }
+                res.extract.insert(archive, info);
+            }
+        }
+        res.vars = vars.into_iter().filter(|(name, _)| defined.contains(name)).collect();
         Ok(res)
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// The error reported when an entry has both the `mode` and `executable`
+/// options.
+const MODE_CONFLICT: &str = "`mode` and `executable` cannot both be given";
+
+/// Checks an entry in the `extract` section for invalid combinations of
+/// options, along with the archives nested inside of it. Nested archives are
+/// extracted along with the archive that contains them, so they cannot have
+/// options that change how or when an archive is extracted.
+fn validate_extract(archive: &str, info: &ExtractInfo, nested: bool) -> errors::Result<()> {
+    if let ExtractInfo::Detailed(opts) = info {
+        if nested && (opts.stream || opts.partial || opts.delete_after_extract || opts.when.is_some()) {
+            return Err(errors::invalid_entry(
+                "extract", archive, "`stream`, `partial`, `delete_after_extract`, and `when` \
+                                     cannot be used for nested archives"));
+        }
+        if opts.directory.is_some() != opts.files.is_empty() {
+            return Err(errors::invalid_entry(
+                "extract", archive, "exactly one of `directory` and `files` must be given"));
+        }
+        if opts.strip_components.is_some() && opts.directory.is_none() {
+            return Err(errors::invalid_entry(
+                "extract", archive, "`strip_components` can only be used with `directory`"));
+        }
+        if opts.stream && !crate::tar::is_tar(archive) {
+            return Err(errors::invalid_entry(
+                "extract", archive, "only tar archives can be extracted with `stream`, \
+                                     use `delete_after_extract` for other archives"));
+        }
+        if opts.partial && crate::tar::is_tar(archive) {
+            return Err(errors::invalid_entry(
+                "extract", archive, "tar archives cannot be extracted with `partial`, \
+                                     since they can only be read from start to end"));
+        }
+        // Finding the files that match a pattern would require a
+        // request for each file in the archive.
+        if opts.partial && (opts.directory.is_some()
+                            || opts.files.keys().any(|name| crate::extract::is_pattern(name))) {
+            return Err(errors::invalid_entry(
+                "extract", archive, "`partial` can only be used with `files` that are \
+                                     given by name, not with patterns or `directory`"));
+        }
+    }
+    if let ExtractTarget::Mapping(files) = info.target() {
+        let mut entries: Vec<_> = files.iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        for (name, dest) in entries {
+            match dest {
+                Destination::Detailed(opts) if opts.mode.is_some() && opts.executable => {
+                    return Err(errors::invalid_entry("extract", archive, MODE_CONFLICT));
+                },
+                Destination::Nested(opts) => {
+                    if crate::extract::is_pattern(name) {
+                        return Err(errors::invalid_entry(
+                            "extract", archive, &format!("the nested archive {:?} must be given \
+                                                          by name, not by a pattern", name)));
+                    }
+                    validate_extract(&nested_name(name, archive), &opts.extract, true)?;
+                },
+                _ => {}
+            }
+        }
+        crate::extract::Mapping::new(files)
+            .with_context(|_| format!("invalid extract entry {:?}", archive))?;
+    }
+    Ok(())
+}
+
+/// Returns the name used to refer to the archive `name` inside of `archive`.
+pub fn nested_name(name: &str, archive: &str) -> String {
+    format!("{} in {}", name, archive)
+}
+
+/// The state of reading a template and the templates that it includes.
+struct IncludeReader<'a> {
+    /// The variables that can be used in `include` paths.
+    vars: &'a HashMap<String, String>,
+    /// Whether the top-level template is verified with a checksum, in which case
+    /// only local templates may be included.
+    pinned: bool,
+    /// The templates that are currently being read, used to detect cycles.
+    stack: Vec<TemplateSource>,
+    /// The templates that have already been read, which are skipped.
+    seen: HashSet<TemplateSource>,
+    /// The templates that have been read, each after the templates it includes.
+    files: Vec<(String, serde_json::Map<String, JsonValue>)>
+}
+
+impl<'a> IncludeReader<'a> {
+    fn new(vars: &'a HashMap<String, String>, pinned: bool) -> Self {
+        IncludeReader { vars, pinned, stack: Vec::new(), seen: HashSet::new(), files: Vec::new() }
+    }
+
+    /// Reads the template from the given source, followed by the templates that
+    /// it includes. The format of the template is determined from its file
+    /// extension if it is not given, and the template is verified against the
+    /// SHA-256 checksum if one is given. `stack` and `seen` contain
+    /// canonicalized sources.
+    fn read(&mut self,
+            source: &TemplateSource,
+            format: Option<Format>,
+            sha256: Option<&str>)
+            -> errors::Result<()> {
+        let canonical = source.canonicalize()
+            .with_context(|_| format!("could not read {}", source))?;
+        if self.stack.contains(&canonical) {
+            return Err(errors::recursive_include(&source.to_string()));
+        }
+        if !self.seen.insert(canonical.clone()) {
+            return Ok(());
+        }
+
+        let contents = source.read()
+            .with_context(|_| format!("could not read {}", source))?;
+        if let Some(expected) = sha256 {
+            let actual = crate::checksum::sha256_reader(contents.as_bytes())?;
+            if !actual.eq_ignore_ascii_case(expected) {
+                return Err(errors::checksum_mismatch(&source.to_string(), expected, &actual));
+            }
+        }
+        let format = format.or_else(|| source.format()).unwrap_or(Format::Toml);
+        let mut table = parse_table(&contents, format)
+            .with_context(|_| format!("could not load {}", source))?;
+        let includes: Vec<String> = match table.remove("include") {
+            Some(value) => serde_json::from_value(value)
+                .with_context(|_| format!("could not load {}", source))?,
+            None => Vec::new()
+        };
+
+        self.stack.push(canonical);
+        for include in includes {
+            let include = crate::vars::interpolate(&include, self.vars)?;
+            for included in source.join(&include)? {
+                if self.pinned && matches!(included, TemplateSource::Url(_)) {
+                    return Err(errors::unverified_include(&source.to_string(),
+                                                          &included.to_string()));
+                }
+                self.read(&included, None, None)?;
+            }
+        }
+        self.stack.pop();
+
+        self.files.push((source.to_string(), table));
+        Ok(())
+    }
+}
+
+/// Parses the contents of a template file into a table. Every format is
+/// converted to JSON values, which keep the case of keys.
+fn parse_table(contents: &str, format: Format) -> errors::Result<serde_json::Map<String, JsonValue>> {
+    let value = match format {
+        Format::Toml => toml::from_str(contents)?,
+        Format::Json => serde_json::from_str(contents)?,
+        Format::Yaml => {
+            let docs = yaml_rust::YamlLoader::load_from_str(contents)?;
+            match docs.into_iter().next() {
+                Some(doc) => yaml_to_json(doc)?,
+                None => JsonValue::Object(serde_json::Map::new())
+            }
+        }
+    };
+    match value {
+        JsonValue::Object(table) => Ok(table),
+        _ => Err(errors::invalid_template("the template is not a table"))
+    }
+}
+
+/// Converts a YAML value to a JSON value. Keys that are not strings are
+/// converted to strings.
+fn yaml_to_json(value: yaml_rust::Yaml) -> errors::Result<JsonValue> {
+    use yaml_rust::Yaml;
+    Ok(match value {
+        Yaml::Null => JsonValue::Null,
+        Yaml::Boolean(b) => JsonValue::Bool(b),
+        Yaml::Integer(i) => JsonValue::from(i),
+        Yaml::Real(r) => JsonValue::from(r.parse::<f64>()?),
+        Yaml::String(s) => JsonValue::String(s),
+        Yaml::Array(values) => {
+            JsonValue::Array(values.into_iter().map(yaml_to_json).collect::<Result<_, _>>()?)
+        },
+        Yaml::Hash(hash) => {
+            let mut map = serde_json::Map::new();
+            for (key, value) in hash {
+                let key = match yaml_to_json(key)? {
+                    JsonValue::String(s) => s,
+                    other => other.to_string()
+                };
+                map.insert(key, yaml_to_json(value)?);
+            }
+            JsonValue::Object(map)
+        },
+        Yaml::Alias(_) | Yaml::BadValue => {
+            return Err(errors::invalid_template("unsupported YAML value"));
+        }
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// Where a template is read from.
+pub enum TemplateSource {
+    /// A file on disk.
+    File(PathBuf),
+    /// Standard input.
+    Stdin,
+    /// An HTTP or HTTPS URL.
+    Url(Url)
+}
+
+impl TemplateSource {
+    /// Interprets a command line argument as a template source. `-` refers to
+    /// standard input, and arguments starting with `http://` or `https://` are
+    /// URLs. Anything else is a file path.
+    pub fn from_os_str(s: &OsStr) -> Self {
+        if s == "-" {
+            return TemplateSource::Stdin;
+        }
+        if let Some(s) = s.to_str() {
+            if s.starts_with("http://") || s.starts_with("https://") {
+                if let Ok(url) = Url::parse(s) {
+                    return TemplateSource::Url(url);
+                }
+            }
+        }
+        TemplateSource::File(PathBuf::from(s))
+    }
+
+    /// Reads the contents of the template.
+    pub fn read(&self) -> errors::Result<String> {
+        let mut contents = String::new();
+        match self {
+            TemplateSource::File(path) => {
+                fs::File::open(path)?.read_to_string(&mut contents)?;
+            },
+            TemplateSource::Stdin => {
+                io::stdin().read_to_string(&mut contents)?;
+            },
+            TemplateSource::Url(url) => {
+                let mut response = reqwest::get(url.clone())?;
+                if !response.status().is_success() {
+                    return Err(errors::status_code(response.status()));
+                }
+                contents = response.text()?;
+            }
+        }
+        Ok(contents)
+    }
+
+    /// Returns a source that refers to the same template, but that can be
+    /// compared with other sources to determine if they refer to the same
+    /// template.
+    fn canonicalize(&self) -> io::Result<Self> {
+        match self {
+            TemplateSource::File(path) => fs::canonicalize(path).map(TemplateSource::File),
+            _ => Ok(self.clone())
+        }
+    }
+
+    /// Determines the format of the template from its file extension.
+    fn format(&self) -> Option<Format> {
+        match self {
+            TemplateSource::File(path) => Format::from_path(path),
+            TemplateSource::Stdin => None,
+            TemplateSource::Url(url) => Format::from_path(Path::new(url.path()))
+        }
+    }
+
+    /// Resolves an included path relative to this template. Included files
+    /// are relative to the directory of the including template, or to the
+    /// current directory for standard input, and may be glob patterns. Included
+    /// URLs are relative to the URL of the including template.
+    fn join(&self, include: &str) -> errors::Result<Vec<TemplateSource>> {
+        let base_dir = match self {
+            TemplateSource::File(path) => path.parent().unwrap_or_else(|| Path::new("")),
+            TemplateSource::Stdin => Path::new(""),
+            TemplateSource::Url(url) => {
+                let url = url.join(include)?;
+                return Ok(vec![TemplateSource::Url(url)]);
+            }
+        };
+        let include = base_dir.join(include);
+        let include_str = include.to_string_lossy();
+        if include_str.contains(&['*', '?', '['][..]) {
+            let mut paths = glob::glob(&include_str)?.collect::<Result<Vec<_>, _>>()?;
+            paths.sort();
+            Ok(paths.into_iter().map(TemplateSource::File).collect())
+        } else {
+            Ok(vec![TemplateSource::File(include)])
+        }
+    }
+}
+
+impl fmt::Display for TemplateSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TemplateSource::File(path) => write!(f, "{}", path.display()),
+            TemplateSource::Stdin => write!(f, "standard input"),
+            TemplateSource::Url(url) => write!(f, "{}", url)
+        }
+    }
+}
+
+/// Substitutes variables into every string and object key in the value.
+fn interpolate_value(value: &mut JsonValue, vars: &HashMap<String, String>) -> errors::Result<()> {
+    match value {
+        JsonValue::String(s) => {
+            *s = crate::vars::interpolate(s, vars)?;
+        },
+        JsonValue::Object(map) => {
+            for (key, mut v) in std::mem::replace(map, serde_json::Map::new()) {
+                interpolate_value(&mut v, vars)?;
+                let key = crate::vars::interpolate(&key, vars)?;
+                if map.contains_key(&key) {
+                    return Err(errors::duplicate_key(&key));
+                }
+                map.insert(key, v);
+            }
+        },
+        JsonValue::Array(values) => {
+            for v in values {
+                interpolate_value(v, vars)?;
+            }
+        },
+        _ => {}
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize)]
 #[serde(untagged)]
+/// Indicates where a file should be downloaded from.
+pub enum RetrieveInfo {
+    /// The file should be downloaded from the given URL.
+    Url(url_serde::SerdeUrl),
+    /// The file should be downloaded using the given options.
+    Detailed(RetrieveOptions)
+}
+
+impl RetrieveInfo {
+    /// Returns the URL that the file should be downloaded from.
+    pub fn url(&self) -> &Url {
+        match self {
+            RetrieveInfo::Url(url) => url,
+            RetrieveInfo::Detailed(opts) => &opts.url
+        }
+    }
+
+    /// Returns the URLs that the file can be downloaded from if the main URL
+    /// fails, in the order that they should be tried.
+    pub fn mirrors(&self) -> Vec<&Url> {
+        match self {
+            RetrieveInfo::Url(_) => Vec::new(),
+            RetrieveInfo::Detailed(opts) => opts.mirrors.iter().map(|url| &**url).collect()
+        }
+    }
+
+    /// Returns the expected size of the file in bytes, if any.
+    pub fn size(&self) -> Option<u64> {
+        match self {
+            RetrieveInfo::Url(_) => None,
+            RetrieveInfo::Detailed(opts) => opts.size
+        }
+    }
+
+    /// Returns true if a failure to download the file should not cause the
+    /// program to fail.
+    pub fn is_optional(&self) -> bool {
+        match self {
+            RetrieveInfo::Url(_) => false,
+            RetrieveInfo::Detailed(opts) => opts.optional
+        }
+    }
+
+    /// Returns the expected SHA-256 checksum of the file, if any.
+    pub fn sha256(&self) -> Option<&str> {
+        match self {
+            RetrieveInfo::Url(_) => None,
+            RetrieveInfo::Detailed(opts) => opts.sha256.as_deref()
+        }
+    }
+
+    /// Returns the permissions to set on the file once it is downloaded, if
+    /// any.
+    pub fn file_mode(&self) -> Option<FileMode> {
+        match self {
+            RetrieveInfo::Url(_) => None,
+            RetrieveInfo::Detailed(opts) => file_mode(opts.mode, opts.executable)
+        }
+    }
+
+    /// Returns true if the file should be decompressed while it is downloaded
+    /// to the given path. Unless `decompress` is given, files are decompressed
+    /// if the URL has the extension of a compression format and the path
+    /// does not.
+    pub fn decompress(&self, path: &str) -> bool {
+        let explicit = match self {
+            RetrieveInfo::Url(_) => None,
+            RetrieveInfo::Detailed(opts) => opts.decompress
+        };
+        explicit.unwrap_or_else(|| crate::decompress::is_implied(self.url().path(), path))
+    }
+
+    /// Returns the groups that the file belongs to.
+    pub fn groups(&self) -> &[String] {
+        match self {
+            RetrieveInfo::Url(_) => &[],
+            RetrieveInfo::Detailed(opts) => &opts.groups
+        }
+    }
+
+    /// Returns the platforms that the file should be retrieved for, if the
+    /// file should not be retrieved for every platform.
+    pub fn when(&self) -> Option<&Condition> {
+        match self {
+            RetrieveInfo::Url(_) => None,
+            RetrieveInfo::Detailed(opts) => opts.when.as_ref()
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for RetrieveInfo {
+    // Implemented by hand instead of with `#[serde(untagged)]` so that
+    // malformed URLs produce a useful error message.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct InfoVisitor;
+
+        impl<'de> Visitor<'de> for InfoVisitor {
+            type Value = RetrieveInfo;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "a URL or a table of retrieve options")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+                Url::parse(value)
+                    .map(|url| RetrieveInfo::Url(url_serde::Serde(url)))
+                    .map_err(|err| E::custom(format!("invalid URL {:?}: {}", value, err)))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                let deserializer = de::value::MapAccessDeserializer::new(map);
+                RetrieveOptions::deserialize(deserializer).map(RetrieveInfo::Detailed)
+            }
+        }
+
+        deserializer.deserialize_any(InfoVisitor)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+/// Options for downloading a single file.
+pub struct RetrieveOptions {
+    /// The URL to download the file from.
+    pub url: url_serde::SerdeUrl,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    /// URLs to try, in order, if downloading from `url` fails.
+    pub mirrors: Vec<url_serde::SerdeUrl>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// The expected size of the file in bytes.
+    pub size: Option<u64>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    /// When true, a failure to download this file is reported as a warning
+    /// instead of an error.
+    pub optional: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// The expected SHA-256 checksum of the file, as a hexadecimal string.
+    pub sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Whether the file is a single compressed file that should be
+    /// decompressed while it is downloaded. If not given, this is determined
+    /// from the file extensions of the URL and the path.
+    pub decompress: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// The Unix permissions to set on the file once it is downloaded.
+    pub mode: Option<Mode>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    /// When true, the file is made executable once it is downloaded.
+    pub executable: bool,
+    #[serde(default, alias = "tags", skip_serializing_if = "Vec::is_empty")]
+    /// The groups that this file belongs to, which can be used to download a
+    /// subset of the files in a template.
+    pub groups: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// The platforms that this file should be retrieved for.
+    pub when: Option<Condition>
+}
+
+impl RetrieveOptions {
+    /// Returns the default options for downloading a file from the given URL.
+    pub fn new(url: Url) -> Self {
+        RetrieveOptions {
+            url: url_serde::Serde(url),
+            mirrors: Vec::new(),
+            size: None,
+            optional: false,
+            sha256: None,
+            decompress: None,
+            mode: None,
+            executable: false,
+            groups: Vec::new(),
+            when: None
+        }
+    }
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Unix permission bits, written in templates as an octal string such as
+/// `"755"`.
+pub struct Mode(pub u32);
+
+impl Serialize for Mode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{:o}", self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for Mode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ModeVisitor;
+
+        impl<'de> Visitor<'de> for ModeVisitor {
+            type Value = Mode;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "an octal string such as \"755\"")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+                match u32::from_str_radix(value, 8) {
+                    Ok(mode) if mode <= 0o7777 => Ok(Mode(mode)),
+                    _ => Err(E::custom(format!(
+                        "invalid mode {:?}, expected an octal string such as \"755\"", value)))
+                }
+            }
+        }
+
+        deserializer.deserialize_str(ModeVisitor)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Permissions that are set on a file once it has been written.
+pub enum FileMode {
+    /// Sets the Unix permission bits of the file.
+    Mode(u32),
+    /// Allows the file to be executed by everyone who can read it.
+    Executable
+}
+
+/// Combines the `mode` and `executable` options of an entry.
+fn file_mode(mode: Option<Mode>, executable: bool) -> Option<FileMode> {
+    match mode {
+        Some(Mode(mode)) => Some(FileMode::Mode(mode)),
+        None if executable => Some(FileMode::Executable),
+        None => None
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+/// Where a file in an archive should be extracted to.
+pub enum Destination {
+    /// The file should be extracted to the given path.
+    Path(String),
+    /// The file should be extracted using the given options.
+    Detailed(DestinationOptions),
+    /// The file is an archive, whose files should be extracted as given.
+    Nested(NestedOptions)
+}
+
+impl<'de> Deserialize<'de> for Destination {
+    // Implemented by hand instead of with `#[serde(untagged)]` so that
+    // mistakes in the options produce a useful error message.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct DestinationVisitor;
+
+        impl<'de> Visitor<'de> for DestinationVisitor {
+            type Value = Destination;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "a path, a table of destination options, or a nested `extract`")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+                Ok(Destination::Path(value.to_owned()))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                // A table with an `extract` key is a nested archive.
+                let deserializer = de::value::MapAccessDeserializer::new(map);
+                let table = serde_json::Map::<String, JsonValue>::deserialize(deserializer)?;
+                let res = if table.contains_key("extract") {
+                    serde_json::from_value(JsonValue::Object(table)).map(Destination::Nested)
+                } else {
+                    serde_json::from_value(JsonValue::Object(table)).map(Destination::Detailed)
+                };
+                res.map_err(de::Error::custom)
+            }
+        }
+
+        deserializer.deserialize_any(DestinationVisitor)
+    }
+}
+
+impl Destination {
+    /// Returns the path to extract the file to. Paths ending in `/` are
+    /// directories. Nested archives are not extracted to a path.
+    pub fn path(&self) -> Option<&str> {
+        match self {
+            Destination::Path(path) => Some(path),
+            Destination::Detailed(opts) => Some(&opts.path),
+            Destination::Nested(_) => None
+        }
+    }
+
+    /// Returns the permissions to set on the file once it is extracted, if
+    /// any.
+    pub fn file_mode(&self) -> Option<FileMode> {
+        match self {
+            Destination::Detailed(opts) => file_mode(opts.mode, opts.executable),
+            _ => None
+        }
+    }
+
+    /// Returns how the files in the archive should be extracted, if the file
+    /// is a nested archive.
+    pub fn nested(&self) -> Option<&ExtractInfo> {
+        match self {
+            Destination::Nested(opts) => Some(&opts.extract),
+            _ => None
+        }
+    }
+}
+
+impl From<String> for Destination {
+    fn from(path: String) -> Self {
+        Destination::Path(path)
+    }
+}
+
+impl From<&str> for Destination {
+    fn from(path: &str) -> Self {
+        Destination::Path(path.to_owned())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+/// Options for extracting a single file from an archive.
+pub struct DestinationOptions {
+    /// The path to extract the file to.
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// The Unix permissions to set on the file once it is extracted, instead
+    /// of the permissions stored in the archive.
+    pub mode: Option<Mode>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    /// When true, the file is made executable once it is extracted.
+    pub executable: bool
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+/// Options for a file in an archive that is itself an archive. Its files are
+/// extracted without storing it at a location of its own.
+pub struct NestedOptions {
+    /// How the files in the nested archive should be extracted.
+    pub extract: Box<ExtractInfo>
+}
+
+#[derive(Debug, Clone)]
 /// Indicates how the files in an archive should be extracted.
 pub enum ExtractInfo {
     /// All files in the archive should be extracted to the given directory.
     Directory(String),
+    /// The archive should be extracted using the given options.
+    Detailed(ExtractOptions),
     /// The files specified in the mapping should be extracted to the specified
     /// locations.
-    Mapping(HashMap<String, String>)
+    Mapping(HashMap<String, Destination>)
+}
+
+impl Serialize for ExtractInfo {
+    // Mappings with an entry named `directory` or `files` are written in the
+    // detailed form, since they could be read as options otherwise.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            ExtractInfo::Directory(dir) => dir.serialize(serializer),
+            ExtractInfo::Detailed(opts) => opts.serialize(serializer),
+            ExtractInfo::Mapping(files)
+                if files.contains_key("directory") || files.contains_key("files") => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry("files", files)?;
+                map.end()
+            },
+            ExtractInfo::Mapping(files) => files.serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ExtractInfo {
+    // A table is treated as `ExtractOptions` if it has a `directory` string or
+    // a `files` table, so that mistakes in the options produce a useful error
+    // message instead of being treated as a mapping. Mappings can still
+    // contain entries named `directory` or `files` that are extracted to other
+    // kinds of destinations, but the detailed form has to be used otherwise.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Directory(String),
+            Table(serde_json::Map<String, JsonValue>)
+        }
+
+        let table = match Raw::deserialize(deserializer)? {
+            Raw::Directory(dir) => return Ok(ExtractInfo::Directory(dir)),
+            Raw::Table(table) => table
+        };
+        let is_options = matches!(table.get("directory"), Some(JsonValue::String(_)))
+            || matches!(table.get("files"), Some(JsonValue::Object(_)));
+        let res = if is_options {
+            serde_json::from_value(JsonValue::Object(table)).map(ExtractInfo::Detailed)
+        } else {
+            serde_json::from_value(JsonValue::Object(table)).map(ExtractInfo::Mapping)
+        };
+        res.map_err(de::Error::custom)
+    }
+}
+
+/// Where the files in an archive should be extracted to.
+pub enum ExtractTarget<'a> {
+    /// All files in the archive should be extracted to the given directory.
+    Directory(&'a str),
+    /// The files specified in the mapping should be extracted to the specified
+    /// locations.
+    Mapping(&'a HashMap<String, Destination>)
+}
+
+impl ExtractInfo {
+    /// Returns where the files in the archive should be extracted to.
+    pub fn target(&self) -> ExtractTarget<'_> {
+        match self {
+            ExtractInfo::Directory(dir) => ExtractTarget::Directory(dir),
+            ExtractInfo::Mapping(files) => ExtractTarget::Mapping(files),
+            ExtractInfo::Detailed(opts) => match &opts.directory {
+                Some(dir) => ExtractTarget::Directory(dir),
+                None => ExtractTarget::Mapping(&opts.files)
+            }
+        }
+    }
+
+    /// Returns the platforms that the archive should be extracted on, if the
+    /// archive should not be extracted on every platform.
+    pub fn when(&self) -> Option<&Condition> {
+        match self {
+            ExtractInfo::Detailed(opts) => opts.when.as_ref(),
+            _ => None
+        }
+    }
+
+    /// Returns how many leading components to remove from the names of the
+    /// files in the archive when extracting it to a directory.
+    pub fn strip_components(&self) -> Option<StripComponents> {
+        match self {
+            ExtractInfo::Detailed(opts) => opts.strip_components,
+            _ => None
+        }
+    }
+
+    /// Returns true if the archive should be extracted while it is
+    /// downloaded.
+    pub fn stream(&self) -> bool {
+        match self {
+            ExtractInfo::Detailed(opts) => opts.stream,
+            _ => false
+        }
+    }
+
+    /// Returns true if the archive should be deleted once it has been
+    /// extracted.
+    pub fn delete_after_extract(&self) -> bool {
+        match self {
+            ExtractInfo::Detailed(opts) => opts.delete_after_extract,
+            _ => false
+        }
+    }
+
+    /// Returns true if the files to extract should be read from the server
+    /// instead of downloading the archive.
+    pub fn partial(&self) -> bool {
+        match self {
+            ExtractInfo::Detailed(opts) => opts.partial,
+            _ => false
+        }
+    }
+
+    /// Returns true if the archive is stored on disk once it has been
+    /// extracted: it is not streamed, read partially, or deleted.
+    pub fn keeps_archive(&self) -> bool {
+        !self.stream() && !self.partial() && !self.delete_after_extract()
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+/// Options for extracting an archive. Exactly one of `directory` and `files`
+/// must be given.
+pub struct ExtractOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// The directory to extract all of the files in the archive to.
+    pub directory: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// The number of leading components to remove from the names of the files
+    /// when extracting to `directory`.
+    pub strip_components: Option<StripComponents>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    /// The files in the archive to extract, mapped to the locations to extract
+    /// them to.
+    pub files: HashMap<String, Destination>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    /// When true, the archive is extracted while it is downloaded instead of
+    /// being stored. Only tar archives can be extracted this way.
+    pub stream: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    /// When true, the archive is deleted once it has been extracted.
+    pub delete_after_extract: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    /// When true, the archive is not downloaded. Instead, the files to extract
+    /// are read from the server using HTTP range requests. Only files in zip
+    /// archives that are given by name can be extracted this way.
+    pub partial: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// The platforms that the archive should be extracted on.
+    pub when: Option<Condition>
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+/// Restricts an entry to certain platforms. Each field may be a single value or
+/// a list of values; the condition matches a platform if each non-empty field
+/// contains the corresponding value of the platform.
+pub struct Condition {
+    #[serde(default, deserialize_with = "one_or_many", skip_serializing_if = "Vec::is_empty")]
+    /// The operating systems to match, e.g. `linux`, `macos`, or `windows`.
+    pub os: Vec<String>,
+    #[serde(default, deserialize_with = "one_or_many", skip_serializing_if = "Vec::is_empty")]
+    /// The architectures to match, e.g. `x86_64`, `x86`, or `aarch64`.
+    pub arch: Vec<String>,
+    #[serde(default, deserialize_with = "one_or_many", skip_serializing_if = "Vec::is_empty")]
+    /// The target triples to match, e.g. `x86_64-unknown-linux-gnu`.
+    pub target: Vec<String>
+}
+
+impl Condition {
+    /// Returns true if the condition matches the given platform.
+    pub fn matches(&self, platform: &Platform) -> bool {
+        let matches = |values: &[String], value: &str| {
+            values.is_empty() || values.iter().any(|v| v == value)
+        };
+        matches(&self.os, &platform.os)
+            && matches(&self.arch, &platform.arch)
+            && matches(&self.target, &platform.target)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How many leading path components are removed from the names of the files
+/// in an archive, like `tar --strip-components`.
+pub enum StripComponents {
+    /// Removes the given number of components. Files with no more components
+    /// than this are not extracted.
+    Count(usize),
+    /// Removes the top-level directory if every file in the archive is inside
+    /// of the same one.
+    Auto
+}
+
+impl Serialize for StripComponents {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            StripComponents::Count(count) => serializer.serialize_u64(*count as u64),
+            StripComponents::Auto => serializer.serialize_str("auto")
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for StripComponents {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Count(usize),
+            Name(String)
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Count(count) => Ok(StripComponents::Count(count)),
+            Raw::Name(ref name) if name == "auto" => Ok(StripComponents::Auto),
+            Raw::Name(name) => Err(de::Error::custom(format!(
+                "invalid value {:?} for `strip_components`, expected a number or \"auto\"",
+                name)))
+        }
+    }
+}
+
+/// Deserializes either a single string or a list of strings.
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>)
+    }
+
+    match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => Ok(vec![value]),
+        OneOrMany::Many(values) => Ok(values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extract_info(json: JsonValue) -> ExtractInfo {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn extract_forms() {
+        let info = extract_info(serde_json::json!({"directory": "out", "strip_components": 1}));
+        assert!(matches!(info.target(), ExtractTarget::Directory("out")));
+        let info = extract_info(serde_json::json!({"files": {"a": "b"}}));
+        assert!(matches!(info, ExtractInfo::Detailed(_)));
+        assert!(matches!(info.target(), ExtractTarget::Mapping(files) if files.contains_key("a")));
+
+        // Entries named like options are read as a mapping if their
+        // destinations cannot be options.
+        let info = extract_info(serde_json::json!({"files": "out/files", "a": "b"}));
+        assert!(matches!(&info, ExtractInfo::Mapping(files) if files.len() == 2));
+        let info = extract_info(serde_json::json!({"directory": {"path": "out/directory"}}));
+        assert!(matches!(&info, ExtractInfo::Mapping(files) if files.contains_key("directory")));
+
+        // Mistakes in the options are reported.
+        let err = serde_json::from_value::<ExtractInfo>(
+            serde_json::json!({"directory": "out", "strip_component": 1})).unwrap_err();
+        assert!(err.to_string().contains("strip_component"), "{}", err);
+    }
+
+    #[test]
+    fn regex_destinations_survive_interpolation() {
+        let path = std::env::temp_dir()
+            .join(format!("tempget-test-{}-regex-vars.toml", std::process::id()));
+        fs::write(&path, r#"
+            [vars]
+            version = "1.2"
+
+            [extract."tool-{version}.zip"]
+            'regex:bin/(?P<name>[a-z]+)-(\d+)' = "tools/$name-v$2-{version}"
+        "#).unwrap();
+        let res = Template::load(&TemplateSource::File(path.clone()), &LoadOptions::default());
+        fs::remove_file(&path).unwrap();
+        let templ = res.unwrap();
+        let info = &templ.extract["tool-1.2.zip"];
+        let files = match info.target() {
+            ExtractTarget::Mapping(files) => files,
+            _ => panic!("expected a mapping")
+        };
+        let mapping = crate::extract::Mapping::new(files).unwrap();
+        let dests: Vec<_> = mapping.destinations("bin/tool-12").into_iter()
+            .map(|(path, _)| path.joined())
+            .collect();
+        assert_eq!(dests, [PathBuf::from("tools/tool-v12-1.2")]);
+    }
+
+    #[test]
+    fn extract_mapping_round_trip() {
+        for key in &["directory", "files"] {
+            let mut files = HashMap::new();
+            files.insert(key.to_string(), Destination::from("out/x"));
+            let json = serde_json::to_value(ExtractInfo::Mapping(files)).unwrap();
+            assert_eq!(json, serde_json::json!({"files": {*key: "out/x"}}));
+            let info = extract_info(json);
+            assert!(matches!(info.target(), ExtractTarget::Mapping(files) if files.contains_key(*key)));
+        }
+        let mut files = HashMap::new();
+        files.insert("a".to_owned(), Destination::from("b"));
+        let json = serde_json::to_value(ExtractInfo::Mapping(files)).unwrap();
+        assert_eq!(json, serde_json::json!({"a": "b"}));
+    }
 }
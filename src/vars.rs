@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+
+use crate::errors;
+
+/// Replaces each `{name}` in the text with the value of the variable `name`.
+/// Variable names are case-insensitive, and `{{` and `}}` are replaced with
+/// `{` and `}`. Braces that do not surround a variable name (such as `{1,3}`)
+/// are left as is.
+pub fn interpolate(text: &str, vars: &HashMap<String, String>) -> errors::Result<String> {
+    interpolate_with(text, |name| {
+        vars.get(name)
+            .cloned()
+            .ok_or_else(|| errors::undefined_variable(name, &format!("{:?}", text)))
+    })
+}
+
+/// Resolves variables that refer to other variables, returning the
+/// variables with all references replaced by their values.
+pub fn resolve(vars: &HashMap<String, String>) -> errors::Result<HashMap<String, String>> {
+    let mut resolved = HashMap::new();
+    for name in vars.keys() {
+        resolve_var(name, vars, &mut resolved, &mut Vec::new())?;
+    }
+    Ok(resolved)
+}
+
+/// Resolves a single variable, given the stack of variables that are
+/// currently being resolved.
+fn resolve_var(name: &str,
+               vars: &HashMap<String, String>,
+               resolved: &mut HashMap<String, String>,
+               stack: &mut Vec<String>) -> errors::Result<String> {
+    if let Some(value) = resolved.get(name) {
+        return Ok(value.clone());
+    }
+    if stack.iter().any(|n| n == name) {
+        return Err(errors::recursive_variable(name));
+    }
+    let text = match vars.get(name) {
+        Some(text) => text,
+        None => {
+            let parent = stack.last().map(String::as_str).unwrap_or_default();
+            return Err(errors::undefined_variable(name, &format!("variable `{}`", parent)));
+        }
+    };
+    stack.push(name.to_owned());
+    let value = interpolate_with(text, |n| resolve_var(n, vars, resolved, stack))?;
+    stack.pop();
+    resolved.insert(name.to_owned(), value.clone());
+    Ok(value)
+}
+
+/// Replaces each variable in the text with the value returned by `lookup`.
+fn interpolate_with<F>(text: &str, mut lookup: F) -> errors::Result<String>
+    where F: FnMut(&str) -> errors::Result<String> {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(&['{', '}'][..]) {
+        result.push_str(&rest[..start]);
+        let tail = &rest[start..];
+        if tail.starts_with("{{") || tail.starts_with("}}") {
+            result.push_str(&tail[..1]);
+            rest = &tail[2..];
+            continue;
+        }
+        match variable_name(tail) {
+            Some(name) => {
+                result.push_str(&lookup(&name.to_lowercase())?);
+                rest = &tail[name.len() + 2..];
+            },
+            None => {
+                result.push_str(&tail[..1]);
+                rest = &tail[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Returns the variable name in `{name}` at the start of the text, if any.
+fn variable_name(text: &str) -> Option<&str> {
+    if !text.starts_with('{') {
+        return None;
+    }
+    let end = text.find('}')?;
+    let name = &text[1..end];
+    let mut chars = name.chars();
+    let valid_start = match chars.next() {
+        Some(c) => c.is_ascii_alphabetic() || c == '_',
+        None => false
+    };
+    if valid_start && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        Some(name)
+    } else {
+        None
+    }
+}
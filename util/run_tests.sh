@@ -46,7 +46,8 @@ for f in test_templates/*; do
         echo "At least one of the $f downloads timed out."
         success=1
         continue
-    elif ! echo "$output" | grep 'downloads failed:' > /dev/null; then
+    elif [[ $exit_code -ne 3 && $exit_code -ne 4 ]]; then
+        # Exit codes 3 and 4 indicate partial and total download failures
         echo "The $f downloads failed due to some unrelated error"
         success=1
         continue